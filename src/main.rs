@@ -2,599 +2,4114 @@
 #[macro_use]
 extern crate rocket;
 
-use rocket::State;
-use std::fs::{File, OpenOptions};
-use std::sync::RwLock;
+use rocket::http::uri::Origin;
+use rocket::http::{ContentType, RawStr, Status};
+use rocket::request::{self, Form, FromRequest};
+use rocket::response::content::Content;
+use rocket::response::{status, Stream};
+use rocket::{Data, Outcome, Request, State};
+use rocket_contrib::json::Json;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::io::{self, Read};
+use std::ops::Bound;
+use std::sync::{Arc, RwLock};
+use std::thread;
+use std::time::Duration;
 
 mod db {
-    use rocksdb::{Direction, IteratorMode, DB};
-    use std::collections::{HashMap, HashSet};
-    use std::fs::File;
-    use std::io::{self, BufRead, BufWriter, Write};
-    use std::str;
-    use std::sync::Mutex;
+    use rocksdb::compaction_filter::Decision;
+    use rocksdb::{Direction, IteratorMode, Options, DB};
+    use std::cmp::Reverse;
+    use std::collections::{BTreeMap, HashMap, HashSet};
+    use std::fs::{self, File, OpenOptions};
+    use std::io::{self, BufWriter, Write};
+    use std::iter::Peekable;
+    use std::ops::Bound;
+    use std::path::{Path, PathBuf};
+    use std::sync::{Arc, Mutex, RwLock};
     use std::time::{SystemTime, UNIX_EPOCH};
-    // Uuid may be reintroduced later with better tx id's
-    //use uuid::Uuid;
 
-    pub struct DBState {
-        pub map: HashMap<String, String>,
-        pub txs: HashMap<String, bool>, // TODO: Commit state should be enum
-        pub db: DB,
-        pub wal: super::File,
-        pub locks: HashMap<String, Mutex<bool>>,
+    #[derive(Clone, Copy, Debug, PartialEq)]
+    pub enum TxState {
+        Pending,
+        // Holds the commit time in milliseconds since the UNIX epoch
+        Committed(u64),
+        Aborted,
     }
 
-    pub struct WalTx {
-        id: String,
+    impl TxState {
+        fn to_wal(self) -> Vec<u8> {
+            match self {
+                TxState::Pending => vec![0],
+                TxState::Committed(ts) => {
+                    let mut buf = vec![1];
+                    buf.extend_from_slice(&ts.to_be_bytes());
+                    buf
+                }
+                TxState::Aborted => vec![2],
+            }
+        }
+
+        fn from_wal(b: &[u8]) -> Option<TxState> {
+            match b {
+                [0] => Some(TxState::Pending),
+                [1, ts @ ..] if ts.len() == 8 => Some(TxState::Committed(bytes_to_u64(ts))),
+                [2] => Some(TxState::Aborted),
+                _ => None,
+            }
+        }
+
+        pub fn is_committed(self) -> bool {
+            matches!(self, TxState::Committed(_))
+        }
     }
 
-    pub fn wal_new_tx(db: &mut DBState) -> WalTx {
-        let id = SystemTime::now()
+    fn now_millis() -> u64 {
+        SystemTime::now()
             .duration_since(UNIX_EPOCH)
             .expect("Time went backwards.")
-            .as_nanos()
-            .to_string();
-        let tx = WalTx { id };
-        let mut w = BufWriter::new(&db.wal);
-        writeln!(w, "{}:false", tx.id).unwrap();
-        db.txs.insert(tx.id.clone(), false);
-        w.flush().unwrap();
-        tx
-    }
-
-    pub fn wal_append_set(db: &DBState, tx: &WalTx, key: &str, value: &str) -> io::Result<()> {
-        let mut w = BufWriter::new(&db.wal);
-        writeln!(w, "{}:{}:{}", tx.id, &key, &value).unwrap();
-        w.flush()
+            .as_millis() as u64
     }
 
-    pub fn wal_commit(db: &mut DBState, tx: &WalTx) -> io::Result<()> {
-        let mut w = BufWriter::new(&db.wal);
-        writeln!(w, "{}:true", tx.id).unwrap();
-        db.txs.insert(tx.id.clone(), true);
-        w.flush()
+    // Newest committed value of recently used keys, evicting the least
+    // recently used ones once the keys and values take up more than
+    // `capacity` bytes.
+    pub struct Cache {
+        entries: HashMap<String, (String, u64)>,
+        // Keys by when they were last used, oldest first
+        lru: BTreeMap<u64, String>,
+        tick: u64,
+        size: usize,
+        pub capacity: usize,
     }
 
-    fn is_meta(k: &str) -> bool {
-        k.starts_with("**")
-    }
+    const CACHE_CAPACITY: usize = 64 * 1024 * 1024;
 
-    pub fn initialize_db(db_path: String, wal_file: File) -> DBState {
-        let mut txs = HashMap::new();
-        let wal_buf = io::BufReader::new(&wal_file);
-        for line in wal_buf.lines() {
-            let entry = line.unwrap();
-            let parts: Vec<&str> = entry.split(':').collect();
-            if parts.len() == 2 {
-                let tx_id = parts[0].to_owned();
-                if parts[1] == "true" {
-                    txs.insert(tx_id, true);
-                } else {
-                    txs.insert(tx_id, false);
-                }
+    impl Cache {
+        pub fn new(capacity: usize) -> Cache {
+            Cache {
+                entries: HashMap::new(),
+                lru: BTreeMap::new(),
+                tick: 0,
+                size: 0,
+                capacity,
             }
         }
 
-        let db = DB::open_default(db_path).unwrap();
-        let db_iter = db.iterator(IteratorMode::Start);
-        let mut map: HashMap<String, String> = HashMap::new();
-        for (key, value) in db_iter {
-            let data = String::from(str::from_utf8(&*key).unwrap());
-            let key = String::from(str::from_utf8(&*key).unwrap());
-            if is_meta(&key) {
-                continue;
+        pub fn get(&mut self, key: &str) -> Option<String> {
+            self.tick += 1;
+            let (value, used) = self.entries.get_mut(key)?;
+            self.lru.remove(used);
+            self.lru.insert(self.tick, key.to_string());
+            *used = self.tick;
+            Some(value.clone())
+        }
+
+        pub fn insert(&mut self, key: String, value: String) {
+            self.remove(&key);
+            self.tick += 1;
+            self.size += key.len() + value.len();
+            self.lru.insert(self.tick, key.clone());
+            self.entries.insert(key, (value, self.tick));
+            while self.size > self.capacity {
+                let oldest = match self.lru.keys().next() {
+                    Some(used) => *used,
+                    None => break,
+                };
+                let key = self.lru.remove(&oldest).unwrap();
+                self.remove(&key);
             }
+        }
 
-            let tx_id = data_tx_id(&data);
-            let value = bytes_to_string(&value);
-            match txs.get(&tx_id) {
-                Some(true) => map.insert(key, value),
-                _ => None,
-            };
+        pub fn remove(&mut self, key: &str) {
+            if let Some((value, used)) = self.entries.remove(key) {
+                self.size -= key.len() + value.len();
+                self.lru.remove(&used);
+            }
         }
 
-        let locks = HashMap::new();
+        pub fn len(&self) -> usize {
+            self.entries.len()
+        }
 
-        DBState {
-            map,
-            txs,
-            wal: wal_file,
-            db,
-            locks,
+        pub fn is_empty(&self) -> bool {
+            self.entries.is_empty()
         }
     }
 
-    fn persist_entry(
-        db: &DBState,
-        key: &str,
-        value: &str,
-        tx: &WalTx,
-    ) -> Result<(), rocksdb::Error> {
-        let key = format!("{}:{}", key, tx.id);
-        db.db.put(key.as_bytes(), value.as_bytes())
+    pub struct DBState {
+        // Filled on reads by `mem_get` and on commit. Behind a mutex so that
+        // reads only need a shared borrow of the db.
+        pub cache: Mutex<Cache>,
+        // Transactions that haven't finished yet. The final state of every
+        // other transaction is kept in RocksDB, see `tx_state`.
+        pub txs: HashMap<u64, TxState>,
+        // Any tx below this without a state record in RocksDB committed
+        pub tx_floor: u64,
+        pub db: DB,
+        pub wal: Wal,
+        pub locks: HashMap<String, Mutex<bool>>,
+        pub next_tx_id: u64,
+        pub open_txs: HashMap<u64, Transaction>,
+        pub ssi_committed: Vec<SsiRecord>,
+        // Shared with the compaction filter, see `vacuum_filter`
+        pub aborted_txs: Arc<RwLock<HashSet<u64>>>,
+        // History from before this tx may have been removed by `vacuum`
+        pub vacuumed_below: u64,
+        // Bytes of torn or corrupt records dropped from the end of the WAL
+        // during the last startup.
+        pub wal_discarded_bytes: u64,
     }
 
-    fn bytes_to_string(v: &[u8]) -> String {
-        String::from(str::from_utf8(v).unwrap())
+    pub struct Wal {
+        dir: PathBuf,
+        file: File,
+        segment: u64,
+        // Start a new segment once the active one grows past this many bytes
+        pub segment_size: u64,
+        // The last tx whose effects were durable in RocksDB at the most
+        // recent checkpoint
+        pub checkpoint: u64,
+    }
+
+    pub struct WalTx {
+        id: u64,
+        // Transactions that were still running when this one started
+        in_progress: HashSet<u64>,
     }
 
-    fn data_tx_id(val: &str) -> String {
-        let parts: Vec<&str> = val.split(':').collect();
-        parts[1].to_owned()
+    // Meta key holding the next tx id to hand out, so that ids keep
+    // increasing across restarts.
+    const NEXT_TX_ID_KEY: &str = "**next_tx_id**";
+
+    // Meta key range holding the final state of each tx, keyed by its
+    // big-endian id.
+    const TX_STATE_PREFIX: &[u8] = b"**tx**";
+    const TX_FLOOR_KEY: &str = "**tx_floor**";
+    const VACUUMED_BELOW_KEY: &str = "**vacuumed_below**";
+
+    fn tx_state_key(id: u64) -> Vec<u8> {
+        let mut key = TX_STATE_PREFIX.to_vec();
+        key.extend_from_slice(&id.to_be_bytes());
+        key
     }
 
-    fn data_value(val: &str) -> String {
-        let parts: Vec<&str> = val.split(':').collect();
-        parts[0].to_owned()
+    fn persist_tx_state(db: &DB, id: u64, state: TxState) {
+        db.put(tx_state_key(id), state.to_wal())
+            .expect("Failed to write tx state.");
     }
 
-    pub fn get_mutex<'a>(
-        locks: &'a mut HashMap<String, Mutex<bool>>,
-        key: &str,
-    ) -> &'a Mutex<bool> {
-        if !locks.contains_key(key) {
-            let mutex = Mutex::new(true);
-            locks.insert(key.to_string(), mutex);
+    pub fn tx_state(db: &DBState, id: u64) -> Option<TxState> {
+        if let Some(state) = db.txs.get(&id) {
+            return Some(*state);
+        }
+        if db.aborted_txs.read().unwrap().contains(&id) {
+            return Some(TxState::Aborted);
+        }
+        match db.db.get(tx_state_key(id)).unwrap() {
+            Some(v) => TxState::from_wal(&v),
+            // The record was compacted away, along with its commit time
+            None if id < db.tx_floor => Some(TxState::Committed(0)),
+            None => None,
         }
-
-        locks.get(key).unwrap()
     }
 
-    pub fn set(mut db: &mut DBState, key: String, value: String) -> Result<String, String> {
-        // Create an uncommitted WAL record and add a entry for each IO change
-        // Commit after last entry added
-        let tx = wal_new_tx(&mut db);
-        wal_append_set(&db, &tx, &key, &value).unwrap();
-        let result_str = format!("Set key: {} to value: {}", key, value);
-        match persist_entry(db, &key, &value, &tx) {
-            Ok(_) => {
-                wal_commit(&mut db, &tx).unwrap();
-                db.map.insert(key, value);
-                Ok(result_str)
+    // Once a tx is older than every pending one, all readers agree on whether
+    // it committed: it is either below their snapshot or in their set of
+    // in-progress transactions. So raise the floor to the oldest pending tx
+    // and drop the committed records below it. Aborted records are kept until
+    // vacuum has removed their versions.
+    fn compact_tx_states(db: &mut DBState) {
+        let horizon = db.txs.keys().cloned().min().unwrap_or(db.next_tx_id);
+        if horizon <= db.tx_floor {
+            return;
+        }
+        // Raise the floor first so that a crash part way through never leaves
+        // a committed tx above the floor without a record.
+        db.db
+            .put(TX_FLOOR_KEY.as_bytes(), horizon.to_string().as_bytes())
+            .expect("Failed to write tx floor.");
+        let start = tx_state_key(db.tx_floor);
+        let end = tx_state_key(horizon);
+        for (key, value) in db
+            .db
+            .iterator(IteratorMode::From(&start, Direction::Forward))
+        {
+            if key[..] >= end[..] {
+                break;
+            }
+            if matches!(TxState::from_wal(&value), Some(TxState::Committed(_))) {
+                db.db.delete(&key).expect("Failed to delete tx state.");
             }
-            _ => Err(String::from("Failed to write")),
         }
+        db.tx_floor = horizon;
     }
 
-    pub fn multi_set(
-        mut db: &mut DBState,
-        keyvals: HashMap<String, String>,
-    ) -> Result<String, String> {
-        let tx = wal_new_tx(db);
-        let mut result_str = "".to_string();
-        for (key, value) in keyvals.iter() {
-            wal_append_set(&db, &tx, &key, &value).unwrap();
-            persist_entry(db, &key, &value, &tx).unwrap();
-            db.map.insert(key.to_string(), value.to_string());
-            let partial_result = format!("Set key: {} to value: {};", key, value);
-            result_str.push_str(&partial_result);
-        }
-        wal_commit(&mut db, &tx).unwrap();
-        Ok(result_str)
-    }
-
-    pub fn get(mut db: &mut DBState, key: String) -> Result<String, String> {
-        let tx = wal_new_tx(db);
-
-        let null_term_key = format!("{}:9", key);
-        let db_iter = db.db.iterator(IteratorMode::From(
-            null_term_key.as_bytes(),
-            Direction::Reverse,
-        ));
-        for (k, value) in db_iter {
-            let k = bytes_to_string(&k);
-
-            let write_tx_id = data_tx_id(&k);
-            let k = data_value(&k);
-            // TODO: Handle the case where the write tx is PENDING,
-            //       may need a mutex
-            let is_committed = match db.txs.get(&write_tx_id) {
-                Some(b) => *b,
-                _ => false,
-            };
+    fn next_tx_id(db: &mut DBState) -> u64 {
+        let id = db.next_tx_id;
+        db.next_tx_id += 1;
+        db.db
+            .put(
+                NEXT_TX_ID_KEY.as_bytes(),
+                db.next_tx_id.to_string().as_bytes(),
+            )
+            .expect("Failed to write next tx id.");
+        id
+    }
+
+    // The WAL starts with a magic string and format version, followed by
+    // records of the form:
+    //
+    //   len: u32 | crc32(payload): u32 | payload
+    //
+    // where the payload is a record type, the tx id and a type specific body.
+    // All integers are big endian.
+    const WAL_MAGIC: &[u8] = b"EZAWAL";
+    const WAL_VERSION: u8 = 1;
+    const WAL_HEADER_LEN: usize = 7;
+
+    // Body is the encoded TxState
+    const WAL_STATE: u8 = 0x01;
+    // Body is the key length as a u32, the key and the encoded value
+    const WAL_WRITE: u8 = 0x02;
+    // Body is the state of every pending transaction, see `encode_checkpoint`.
+    // The tx id is the last tx whose effects are durable in RocksDB.
+    const WAL_CHECKPOINT: u8 = 0x03;
+
+    // The WAL is split into numbered segment files inside a directory. When
+    // the active segment outgrows `Wal::segment_size` a new one is started
+    // with a checkpoint record, and the older segments are deleted.
+    const WAL_SEGMENT_SIZE: u64 = 4 * 1024 * 1024;
+
+    fn wal_segment_path(dir: &Path, segment: u64) -> PathBuf {
+        dir.join(format!("{:010}.wal", segment))
+    }
 
-            if key == k && (write_tx_id < tx.id && is_committed) {
-                let value = bytes_to_string(&value);
-                return Ok(value);
+    // Numbers of the segments in `dir`, oldest first
+    fn wal_segments(dir: &Path) -> io::Result<Vec<u64>> {
+        let mut segments = Vec::new();
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            if path.extension().and_then(|e| e.to_str()) == Some("wal") {
+                if let Some(n) = path.file_stem().and_then(|s| s.to_str()?.parse().ok()) {
+                    segments.push(n);
+                }
             }
         }
-
-        wal_commit(&mut db, &tx).unwrap();
-        Err(String::from("Not found!"))
+        segments.sort_unstable();
+        Ok(segments)
     }
 
-    pub fn scan(mut db: &mut DBState, start: String, end: String) -> Vec<String> {
-        // Start with the first key found
-        // Keep going until the end key found
-        let tx = wal_new_tx(db);
-        let mut values: Vec<String> = Vec::new();
-        let null_term_end = format!("{}:9", end);
-        let db_iter = db.db.iterator(IteratorMode::From(
-            null_term_end.as_bytes(),
-            Direction::Reverse,
-        ));
-        let mut found_keys = HashSet::new();
-        for (k, value) in db_iter {
-            let k = bytes_to_string(&k);
-            let write_tx_id = data_tx_id(&k);
-            let k = data_value(&k);
+    fn wal_open_segment(dir: &Path, segment: u64) -> io::Result<File> {
+        let file = OpenOptions::new()
+            .create(true)
+            .read(true)
+            .append(true)
+            .open(wal_segment_path(dir, segment))?;
+        if file.metadata()?.len() == 0 {
+            wal_write_record(&file, &wal_header())?;
+        }
+        Ok(file)
+    }
 
-            if k < start {
-                break;
-            }
+    // Each entry is the tx id, the length of the encoded state and the state
+    fn encode_checkpoint(txs: &HashMap<u64, TxState>) -> Vec<u8> {
+        let mut buf = Vec::new();
+        for (id, state) in txs.iter() {
+            let state = state.to_wal();
+            buf.extend_from_slice(&id.to_be_bytes());
+            buf.push(state.len() as u8);
+            buf.extend_from_slice(&state);
+        }
+        buf
+    }
 
-            let is_committed = *db.txs.get(&write_tx_id).unwrap_or(&false);
-            if write_tx_id < tx.id && is_committed && !found_keys.contains(&k) {
-                let value = bytes_to_string(&value);
-                found_keys.insert(k.clone());
-                values.push(value)
+    fn decode_checkpoint(mut buf: &[u8]) -> HashMap<u64, TxState> {
+        let mut txs = HashMap::new();
+        while buf.len() > 9 {
+            let id = bytes_to_u64(&buf[..8]);
+            let end = (9 + buf[8] as usize).min(buf.len());
+            if let Some(state) = TxState::from_wal(&buf[9..end]) {
+                txs.insert(id, state);
             }
+            buf = &buf[end..];
         }
-        wal_commit(&mut db, &tx).unwrap();
-        values.reverse();
-        values
+        txs
     }
 
-    pub fn mem_get(db: &DBState, key: String) -> Result<String, String> {
-        match db.map.get(&key) {
-            Some(s) => Ok(s.to_string()),
-            _ => Err(String::from("Not found!")),
-        }
+    fn wal_header() -> Vec<u8> {
+        let mut buf = WAL_MAGIC.to_vec();
+        buf.push(WAL_VERSION);
+        buf
     }
 
-    fn table_next_id(db: &mut DBState, table: &str) -> u64 {
-        let auto_inc_key = format!("**autoincrement**{}", table);
-        let mutex = get_mutex(&mut db.locks, &auto_inc_key);
-        let next_id = {
-            let _m = mutex.lock().unwrap();
-            let next_id = match db.db.get(auto_inc_key.as_bytes()).unwrap() {
-                Some(v) => bytes_to_string(&v),
-                None => "0".to_string(),
-            };
-            let next_id: u64 = next_id.parse().unwrap();
-            next_id
-        };
-        db.db
-            .put(
-                auto_inc_key.as_bytes(),
-                format!("{}", next_id + 1).as_bytes(),
-            )
-            .expect("Failed to write new auto inc id.");
-        next_id
+    fn wal_record(kind: u8, tx_id: u64, body: &[u8]) -> Vec<u8> {
+        let mut payload = vec![kind];
+        payload.extend_from_slice(&tx_id.to_be_bytes());
+        payload.extend_from_slice(body);
+
+        let mut buf = Vec::with_capacity(payload.len() + 8);
+        buf.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+        buf.extend_from_slice(&checksum(&payload).to_be_bytes());
+        buf.extend_from_slice(&payload);
+        buf
     }
 
-    fn table_name(s: &str) -> String {
-        let parts: Vec<&str> = s.split(':').collect();
-        parts[1].to_owned()
+    fn checksum(payload: &[u8]) -> u32 {
+        let mut hasher = crc32fast::Hasher::new();
+        hasher.update(payload);
+        hasher.finalize()
     }
 
-    fn primary_key(s: &str) -> u64 {
-        let parts: Vec<&str> = s.split(':').collect();
-        parts[1].to_owned().parse().unwrap()
+    // Splits the next record off the front of `buf`, returning its payload and
+    // total length. Returns `None` if the record is truncated or its checksum
+    // does not match.
+    fn wal_next_record(buf: &[u8]) -> Option<(&[u8], usize)> {
+        if buf.len() < 8 {
+            return None;
+        }
+        let len = u32::from_be_bytes([buf[0], buf[1], buf[2], buf[3]]) as usize;
+        let crc = u32::from_be_bytes([buf[4], buf[5], buf[6], buf[7]]);
+        let payload = buf.get(8..8 + len)?;
+        if len < 9 || checksum(payload) != crc {
+            return None;
+        }
+        Some((payload, 8 + len))
     }
 
-    fn col(s: &str) -> String {
-        let parts: Vec<&str> = s.split(':').collect();
-        parts[2].to_owned()
+    fn wal_write_record(wal: &File, record: &[u8]) -> io::Result<()> {
+        let mut w = BufWriter::new(wal);
+        w.write_all(record)?;
+        w.flush()
     }
 
-    fn row_tx_id(s: &str) -> String {
-        let parts: Vec<&str> = s.split(':').collect();
-        parts
-            .last()
-            .expect("Could not get tx id from row-entry key")
-            .to_string()
+    // Final states are also written to RocksDB if `persist` is set, which has
+    // to happen before a checkpoint can drop them from the WAL.
+    fn wal_set_state(
+        db: &mut DBState,
+        tx: &WalTx,
+        state: TxState,
+        persist: bool,
+    ) -> io::Result<()> {
+        wal_write_record(&db.wal.file, &wal_record(WAL_STATE, tx.id, &state.to_wal()))?;
+        if state == TxState::Pending {
+            db.txs.insert(tx.id, state);
+        } else {
+            if persist {
+                persist_tx_state(&db.db, tx.id, state);
+            }
+            db.txs.remove(&tx.id);
+        }
+        if state == TxState::Aborted {
+            db.aborted_txs.write().unwrap().insert(tx.id);
+        }
+        if db.wal.file.metadata()?.len() >= db.wal.segment_size {
+            wal_checkpoint(db)?;
+        }
+        Ok(())
     }
 
-    fn is_committed(db: &DBState, tx_id: &str) -> bool {
-        match db.txs.get(tx_id) {
-            Some(b) => *b,
-            _ => false,
+    // Starts a new segment with a checkpoint and deletes the older ones.
+    // Writes and final tx states reach RocksDB before the WAL moves on, so
+    // once RocksDB is flushed the only thing recovery still needs from the
+    // old segments is which transactions are pending, which the checkpoint
+    // carries over.
+    pub fn wal_checkpoint(db: &mut DBState) -> io::Result<()> {
+        db.db.flush().expect("Failed to flush RocksDB.");
+        // Pending transactions may still write, everything before them is done
+        let durable = db
+            .txs
+            .iter()
+            .filter(|(_, state)| **state == TxState::Pending)
+            .map(|(id, _)| *id)
+            .min()
+            .unwrap_or(db.next_tx_id)
+            - 1;
+
+        let segment = db.wal.segment + 1;
+        let file = wal_open_segment(&db.wal.dir, segment)?;
+        let body = encode_checkpoint(&db.txs);
+        wal_write_record(&file, &wal_record(WAL_CHECKPOINT, durable, &body))?;
+        db.wal.file = file;
+        db.wal.segment = segment;
+        db.wal.checkpoint = durable;
+
+        for old in wal_segments(&db.wal.dir)? {
+            if old < segment {
+                fs::remove_file(wal_segment_path(&db.wal.dir, old))?;
+            }
         }
+        compact_tx_states(db);
+        Ok(())
+    }
+
+    pub fn wal_new_tx(db: &mut DBState) -> WalTx {
+        // Single-call transactions finish while holding the db, so the only
+        // other transactions that can be running are the open ones.
+        let tx = WalTx {
+            id: next_tx_id(db),
+            in_progress: db.open_txs.keys().cloned().collect(),
+        };
+        wal_set_state(db, &tx, TxState::Pending, false).unwrap();
+        tx
     }
 
-    fn insert_secondary_index(
+    // Logs a write of the key identified by `prefix`. A `None` value logs a
+    // delete.
+    pub fn wal_append_write(
         db: &DBState,
-        table: &str,
-        col: &str,
-        val: &str,
-        id: u64,
         tx: &WalTx,
-    ) -> Result<String, String> {
-        let encoded_key = format!("{}:{}:{}", table, col, val);
-        persist_entry(&db, &encoded_key, &id.to_string(), tx)
-            .expect("Failed to persist secondary index");
-        Ok("Successfully inserted secondary index".to_string())
+        prefix: &[u8],
+        value: &[u8],
+    ) -> io::Result<()> {
+        let mut body = (prefix.len() as u32).to_be_bytes().to_vec();
+        body.extend_from_slice(prefix);
+        body.extend_from_slice(value);
+        wal_write_record(&db.wal.file, &wal_record(WAL_WRITE, tx.id, &body))
     }
 
-    pub fn insert_row(
-        mut db: &mut DBState,
-        table: &str,
-        colvals: &HashMap<String, String>,
-    ) -> Result<u64, String> {
-        let tx = wal_new_tx(db);
-        let id = table_next_id(&mut db, table);
-        // TODO: Do I really need to insert an id entry?
-        persist_entry(db, &format!("{}:id", table), &id.to_string(), &tx)
-            .expect("Could not insert id");
-        for (col, value) in colvals.iter() {
-            let encoded_key = format!("{}:{}:{}", table, id, col);
-            persist_entry(db, &encoded_key, value, &tx).unwrap();
-            insert_secondary_index(&db, table, col, value, id, &tx).unwrap();
+    pub fn wal_commit(db: &mut DBState, tx: &WalTx) -> io::Result<()> {
+        wal_set_state(db, tx, TxState::Committed(now_millis()), true)
+    }
+
+    // Readers only need to know a tx aborted while versions it wrote are
+    // still on disk, so most aborts, which never got as far as `persisted`
+    // writes, are only kept in memory until the next vacuum.
+    pub fn wal_abort(db: &mut DBState, tx: &WalTx, persisted: bool) -> io::Result<()> {
+        wal_set_state(db, tx, TxState::Aborted, persisted)
+    }
+
+    fn is_meta(k: &[u8]) -> bool {
+        k.starts_with(b"**")
+    }
+
+    // Every versioned key in RocksDB starts with a tag naming the family it
+    // belongs to, followed by its components and finally the big-endian id
+    // of the transaction that wrote it. Components are escaped (0x00 becomes
+    // 0x00 0xff) and terminated by 0x00 0x01, so arbitrary bytes are allowed
+    // and the byte order RocksDB sorts by matches the logical tuple order.
+    // All versions of a key therefore sit next to each other, oldest first.
+    const KV_TAG: u8 = 0x01;
+    const ROW_TAG: u8 = 0x02;
+    const INDEX_TAG: u8 = 0x03;
+
+    const TX_ID_LEN: usize = 8;
+
+    fn encode_component(buf: &mut Vec<u8>, component: &[u8]) {
+        for &b in component {
+            buf.push(b);
+            if b == 0x00 {
+                buf.push(0xff);
+            }
         }
-        wal_commit(&mut db, &tx).unwrap();
-        Ok(id)
+        buf.extend_from_slice(&[0x00, 0x01]);
     }
 
-    pub fn update_row(
-        mut db: &mut DBState,
-        table: &str,
-        id: u64,
-        colvals: &HashMap<String, String>,
-    ) -> Result<String, String> {
-        let tx = wal_new_tx(db);
-        for (col, value) in colvals.iter() {
-            let encoded_key = format!("{}:{}:{}", table, id, col);
-            persist_entry(&db, &encoded_key, value, &tx).expect("Could not update record");
+    // Encodes everything but the tx id, which is shared by all versions of a key.
+    fn encode_prefix(tag: u8, components: &[&[u8]]) -> Vec<u8> {
+        let mut buf = vec![tag];
+        for c in components {
+            encode_component(&mut buf, c);
         }
-        wal_commit(&mut db, &tx).expect("Failed to commit update.");
-        Ok("Row successfully updated".to_string())
+        buf
     }
 
-    pub fn get_row(mut db: &mut DBState, table: &str, id: u64) -> HashMap<String, String> {
-        let tx = wal_new_tx(db);
-        // Need to add one to id to force rocksdb to start search
-        // after the last matching key.
-        let search_k = format!("{}:{}", table, id + 1);
-        let mut record = HashMap::new();
-        let mut db_iter = db.db.iterator(IteratorMode::End);
-        db_iter.set_mode(IteratorMode::From(search_k.as_bytes(), Direction::Reverse));
-        for (k, value) in db_iter {
-            let k = bytes_to_string(&k);
-            if is_meta(&k) {
-                continue;
-            }
-            let pk = primary_key(&k);
-            if pk == id {
-                let col = col(&k);
-                let tx_id = row_tx_id(&k);
-                let is_committed = match db.txs.get(&tx_id) {
-                    Some(b) => *b,
-                    _ => false,
-                };
+    fn encode_key(prefix: &[u8], tx_id: u64) -> Vec<u8> {
+        let mut key = prefix.to_vec();
+        key.extend_from_slice(&tx_id.to_be_bytes());
+        key
+    }
+
+    // Smallest key that sorts after every key starting with the given prefix.
+    // Prefixes always end in a component terminator, so the last byte is 0x01
+    // and can't overflow.
+    fn prefix_end(prefix: &[u8]) -> Vec<u8> {
+        let mut end = prefix.to_vec();
+        *end.last_mut().unwrap() += 1;
+        end
+    }
+
+    struct DecodedKey {
+        components: Vec<Vec<u8>>,
+        tx_id: u64,
+    }
 
-                // We insert the newest value for a col first, so we should not
-                // overwrite any existing col entries
-                if !record.contains_key(&col) && is_committed {
-                    record.insert(col, bytes_to_string(&value));
+    fn decode_key(k: &[u8]) -> Option<DecodedKey> {
+        if k.len() < 1 + TX_ID_LEN || is_meta(k) {
+            return None;
+        }
+        let (body, tx_bytes) = k.split_at(k.len() - TX_ID_LEN);
+        let (_, components) = decode_prefix(body)?;
+        Some(DecodedKey {
+            components,
+            tx_id: key_tx_id(tx_bytes),
+        })
+    }
+
+    fn decode_prefix(body: &[u8]) -> Option<(u8, Vec<Vec<u8>>)> {
+        let mut components = Vec::new();
+        let mut current = Vec::new();
+        let mut i = 1;
+        while i < body.len() {
+            match (body[i], body.get(i + 1)) {
+                (0x00, Some(0xff)) => current.push(0x00),
+                (0x00, Some(0x01)) => components.push(std::mem::take(&mut current)),
+                (0x00, _) => return None,
+                (b, _) => {
+                    current.push(b);
+                    i += 1;
+                    continue;
                 }
-            } else if pk < id {
-                break;
             }
+            i += 2;
         }
-        wal_commit(&mut db, &tx).unwrap();
-        record
+        if !current.is_empty() {
+            return None;
+        }
+        Some((body[0], components))
     }
 
-    pub fn get_by_col(
-        mut db: &mut DBState,
-        table: &str,
-        col: String,
-        value: String,
-    ) -> Option<HashMap<String, String>> {
-        // find a key that matches format:
-        // table:col:value
-        // Scan to most recent entry that has tx id less than current
-        let search_k = format!("{}:{}:{}:9", table, col, value);
-        let mut db_iter = db.db.iterator(IteratorMode::End);
-        db_iter.set_mode(IteratorMode::From(search_k.as_bytes(), Direction::Reverse));
-        for (k, value) in db_iter {
-            let k = bytes_to_string(&k);
-            if is_meta(&k) {
-                continue;
+    // Reads the tx id off the end of an encoded key.
+    fn key_tx_id(k: &[u8]) -> u64 {
+        bytes_to_u64(&k[k.len() - TX_ID_LEN..])
+    }
+
+    fn bytes_to_u64(v: &[u8]) -> u64 {
+        let mut buf = [0; 8];
+        buf.copy_from_slice(v);
+        u64::from_be_bytes(buf)
+    }
+
+    // Stored values carry a one byte header so that a tombstone can't be
+    // confused with any real value. A tombstone is written when a key is
+    // deleted; readers that find one as the newest visible version treat the
+    // key as absent, while snapshots older than the delete still see the old
+    // value.
+    const VALUE_TAG: u8 = 0x01;
+    const TOMBSTONE_TAG: u8 = 0x00;
+    // An increment of a counter by a big-endian i64, see `incr`
+    const DELTA_TAG: u8 = 0x02;
+    // A value preceded by the big-endian time in milliseconds at which it
+    // expires. Once expired it reads like a tombstone.
+    const EXPIRING_TAG: u8 = 0x03;
+
+    fn encode_value(value: Option<&[u8]>) -> Vec<u8> {
+        match value {
+            Some(v) => {
+                let mut buf = Vec::with_capacity(v.len() + 1);
+                buf.push(VALUE_TAG);
+                buf.extend_from_slice(v);
+                buf
             }
-            let tx_id = row_tx_id(&k);
-            let is_com = is_committed(&db, &tx_id);
-            if is_com {
-                let row_id: u64 = bytes_to_string(&value).parse().unwrap();
-                return Some(get_row(&mut db, table, row_id));
+            None => vec![TOMBSTONE_TAG],
+        }
+    }
+
+    fn decode_value(v: &[u8]) -> Option<&[u8]> {
+        match v.split_first() {
+            Some((&VALUE_TAG, rest)) => Some(rest),
+            Some((&EXPIRING_TAG, rest)) if bytes_to_u64(&rest[..8]) > now_millis() => {
+                Some(&rest[8..])
             }
+            _ => None,
         }
+    }
 
-        None
+    fn encode_expiring(value: &[u8], expires_at: u64) -> Vec<u8> {
+        let mut buf = vec![EXPIRING_TAG];
+        buf.extend_from_slice(&expires_at.to_be_bytes());
+        buf.extend_from_slice(value);
+        buf
     }
 
-    #[cfg(test)]
-    mod tests {
-        use super::*;
-        use rocksdb::Options;
-        use std::fs::{self, OpenOptions};
+    fn value_expiry(v: &[u8]) -> Option<u64> {
+        match v.split_first() {
+            Some((&EXPIRING_TAG, rest)) => Some(bytes_to_u64(&rest[..8])),
+            _ => None,
+        }
+    }
 
-        fn setup() -> DBState {
-            let db_path = "test_data".to_string();
-            let wal_file = OpenOptions::new()
-                .create(true)
-                .read(true)
-                .append(true)
-                .open("test_wal.db")
-                .unwrap();
-            initialize_db(db_path, wal_file)
+    fn has_lapsed(v: &[u8]) -> bool {
+        matches!(value_expiry(v), Some(expires_at) if expires_at <= now_millis())
+    }
+
+    // Meta key range indexing expiring values by when they expire, so the
+    // reaper only looks at keys that are due. Each entry is the big-endian
+    // expiry time followed by the key's prefix. Entries are left behind when
+    // a key is written again, so the reaper checks the key before deleting
+    // it.
+    const EXPIRY_PREFIX: &[u8] = b"**expires**";
+
+    fn expiry_key(expires_at: u64, prefix: &[u8]) -> Vec<u8> {
+        let mut key = EXPIRY_PREFIX.to_vec();
+        key.extend_from_slice(&expires_at.to_be_bytes());
+        key.extend_from_slice(prefix);
+        key
+    }
+
+    fn index_expiry(db: &DB, prefix: &[u8], value: &[u8]) -> Result<(), rocksdb::Error> {
+        match value_expiry(value) {
+            Some(expires_at) => db.put(expiry_key(expires_at, prefix), b""),
+            None => Ok(()),
         }
+    }
 
-        fn setup_crashed() -> DBState {
-            {
-                // Create temporary handle to the WAL file so that we can
-                // delete it after finishing the setup. This emulates a
-                // "crash" since it appears that nothing was written to the WAL.
-                let db_path = "crashed_test_data".to_string();
-                let setup_wal_file = OpenOptions::new()
-                    .create(true)
-                    .read(true)
-                    .append(true)
-                    .open("crashed_test_wal.db")
-                    .unwrap();
-                let mut setup_state = initialize_db(db_path, setup_wal_file);
-                // keyvals that are part of the failed (i.e. crashed) write
-                let mut crashed_keyvals = HashMap::new();
-                crashed_keyvals.insert("hello".to_string(), "world".to_string());
-                crashed_keyvals.insert("foo".to_string(), "bar".to_string());
-                multi_set(&mut setup_state, crashed_keyvals).expect("Cannot set multiple keys.");
-                fs::remove_file("crashed_test_wal.db").expect("Can't delete crashed wal file.");
-            }
+    fn encode_delta(delta: i64) -> Vec<u8> {
+        let mut buf = vec![DELTA_TAG];
+        buf.extend_from_slice(&delta.to_be_bytes());
+        buf
+    }
 
-            let db_path = "crashed_test_data".to_string();
-            let wal_file = OpenOptions::new()
-                .create(true)
-                .read(true)
-                .append(true)
-                .open("crashed_test_wal.db")
-                .unwrap();
-            let mut state = initialize_db(db_path, wal_file);
-            // The following multiset should work fine so these keyvals are
-            // considered 'good'
-            let mut good_keyvals = HashMap::new();
-            good_keyvals.insert("good_hello".to_string(), "good_world".to_string());
-            good_keyvals.insert("good_foo".to_string(), "good_bar".to_string());
-            multi_set(&mut state, good_keyvals).expect("Cannot set multiple keys.");
-            state
+    fn decode_delta(v: &[u8]) -> Option<i64> {
+        match v.split_first() {
+            Some((&DELTA_TAG, rest)) => Some(bytes_to_u64(rest) as i64),
+            _ => None,
         }
+    }
 
-        fn cleanup() {
-            super::DB::destroy(&Options::default(), "test_data".to_string())
-                .expect("Cannot destroy main test db.");
-            fs::remove_file("test_wal.db").expect("Can't clean up main test wal file.");
+    // A value that isn't a number counts as 0 when it is incremented
+    fn add_to_counter(value: Option<&[u8]>, delta: i64) -> Vec<u8> {
+        let count: i64 = value
+            .and_then(|v| std::str::from_utf8(v).ok())
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(0);
+        count.wrapping_add(delta).to_string().into_bytes()
+    }
+
+    // Folds the visible versions of a key, newest first, into its tx id and
+    // value. Deltas are added up until a full value or tombstone is found to
+    // add them to, so older versions are only read when there are deltas.
+    // Deltas on top of a value that has expired expire along with it.
+    fn resolve_versions<I>(mut versions: I) -> Option<(u64, Option<Vec<u8>>)>
+    where
+        I: Iterator<Item = (u64, Vec<u8>)>,
+    {
+        let (tx_id, value) = versions.next()?;
+        let mut delta = match decode_delta(&value) {
+            Some(delta) => delta,
+            None => return Some((tx_id, decode_value(&value).map(|v| v.to_vec()))),
+        };
+        for (_, value) in versions {
+            match decode_delta(&value) {
+                Some(d) => delta = delta.wrapping_add(d),
+                None if has_lapsed(&value) => return Some((tx_id, None)),
+                None => return Some((tx_id, Some(add_to_counter(decode_value(&value), delta)))),
+            }
         }
-        fn cleanup_crashed() {
-            super::DB::destroy(&Options::default(), "crashed_test_data".to_string())
-                .expect("Cannot destroy crash test db.");
-            fs::remove_file("crashed_test_wal.db").expect("Can't clean up crashed wal file.");
+        Some((tx_id, Some(add_to_counter(None, delta))))
+    }
+
+    pub fn initialize_db(db_path: String, wal_dir: String) -> DBState {
+        let dir = PathBuf::from(wal_dir);
+        // Before WAL segments there was a single text WAL next to where the
+        // segment directory now goes. Its states can't be carried over.
+        let legacy_wal =
+            dir.with_file_name(format!("{}.db", dir.file_name().unwrap().to_string_lossy()));
+        if legacy_wal.exists() {
+            panic!(
+                "Found a WAL in the old text format at {}. Data written by \
+                 earlier versions can't be opened, move it and the data \
+                 directory out of the way to start afresh.",
+                legacy_wal.display()
+            );
         }
+        fs::create_dir_all(&dir).unwrap();
+        let mut log = recovery::read_wal(&dir);
+        let wal_file = wal_open_segment(&dir, log.segment).unwrap();
 
-        #[test]
-        fn test_set() {
-            {
-                let mut db = setup();
-                let res = set(&mut db, String::from("hello"), String::from("world")).unwrap();
-                assert_eq!(res, "Set key: hello to value: world");
-            }
-            cleanup();
+        let aborted_txs = Arc::new(RwLock::new(HashSet::new()));
+        let mut opts = Options::default();
+        opts.create_if_missing(true);
+        opts.set_compaction_filter("vacuum", vacuum_filter(aborted_txs.clone()));
+        let db = DB::open(&opts, db_path).unwrap();
+        if has_legacy_keys(&db) {
+            panic!(
+                "Found keys in the old `key:txid` format. Data written by \
+                 earlier versions can't be opened, move the data directory \
+                 out of the way to start afresh."
+            );
+        }
+        // The persisted counter may lag behind the WAL if we crashed right
+        // after writing a begin record, so never go below what the WAL has seen.
+        let mut next_tx_id = log.next_tx_id;
+        if let Some(v) = db.get(NEXT_TX_ID_KEY.as_bytes()).unwrap() {
+            let persisted: u64 = bytes_to_string(&v).parse().unwrap();
+            next_tx_id = next_tx_id.max(persisted);
         }
+        let tx_floor = match db.get(TX_FLOOR_KEY.as_bytes()).unwrap() {
+            Some(v) => bytes_to_string(&v).parse().unwrap(),
+            None => 0,
+        };
+        let vacuumed_below = match db.get(VACUUMED_BELOW_KEY.as_bytes()).unwrap() {
+            Some(v) => bytes_to_string(&v).parse().unwrap(),
+            None => 0,
+        };
 
-        #[test]
-        fn test_multi_set() {
-            {
-                let mut db = setup();
-                let mut keyvals = HashMap::new();
-                keyvals.insert("hello".to_string(), "world".to_string());
-                keyvals.insert("foo".to_string(), "bar".to_string());
-                multi_set(&mut db, keyvals).unwrap();
-                assert_eq!(
-                    get(&mut db, "hello".to_string()).unwrap(),
-                    "world".to_string()
-                );
-                assert_eq!(get(&mut db, "foo".to_string()).unwrap(), "bar".to_string());
+        recovery::replay(&db, &wal_file, &mut log);
+        let tx_states = db.iterator(IteratorMode::From(TX_STATE_PREFIX, Direction::Forward));
+        for (key, value) in tx_states {
+            if !key.starts_with(TX_STATE_PREFIX) {
+                break;
+            }
+            if TxState::from_wal(&value) == Some(TxState::Aborted) {
+                aborted_txs.write().unwrap().insert(key_tx_id(&key));
             }
-            cleanup();
         }
 
-        #[test]
+        DBState {
+            cache: Mutex::new(Cache::new(CACHE_CAPACITY)),
+            txs: HashMap::new(),
+            tx_floor,
+            wal: Wal {
+                dir,
+                file: wal_file,
+                segment: log.segment,
+                segment_size: WAL_SEGMENT_SIZE,
+                checkpoint: log.checkpoint,
+            },
+            db,
+            locks: HashMap::new(),
+            next_tx_id,
+            open_txs: HashMap::new(),
+            ssi_committed: Vec::new(),
+            aborted_txs,
+            vacuumed_below,
+            wal_discarded_bytes: log.discarded_bytes,
+        }
+    }
+
+    // Keys written before keys were encoded are plain text such as
+    // `key:txid`, which would be misread as encoded keys. Every current key
+    // either starts with one of the tags or is a meta key, so anything
+    // sorting outside of those ranges must be a legacy one.
+    fn has_legacy_keys(db: &DB) -> bool {
+        let first_from = |start: &[u8]| {
+            db.iterator(IteratorMode::From(start, Direction::Forward))
+                .next()
+                .map(|(k, _)| k)
+        };
+        let below_tags = matches!(first_from(&[]), Some(k) if *k < [KV_TAG][..]);
+        let after_tags = matches!(first_from(&[INDEX_TAG + 1]), Some(k) if !is_meta(&k));
+        let after_meta = first_from(b"*+").is_some();
+        below_tags || after_tags || after_meta
+    }
+
+    // Crash recovery. On startup the WAL segments since the last checkpoint
+    // are read back and RocksDB is brought in line with them: writes of
+    // committed transactions are redone in case RocksDB lost them, and
+    // versions written by transactions that never committed are removed.
+    mod recovery {
+        use super::*;
+
+        // What the WAL says happened since the last checkpoint
+        pub struct WalLog {
+            pub txs: HashMap<u64, TxState>,
+            // Tx id, key prefix and encoded value of every logged write
+            pub writes: Vec<(u64, Vec<u8>, Vec<u8>)>,
+            pub next_tx_id: u64,
+            pub checkpoint: u64,
+            // The segment new records should be appended to
+            pub segment: u64,
+            pub discarded_bytes: u64,
+        }
+
+        fn decode_write(body: &[u8]) -> Option<(Vec<u8>, Vec<u8>)> {
+            let len = body.get(..4)?;
+            let len = u32::from_be_bytes([len[0], len[1], len[2], len[3]]) as usize;
+            let prefix = body.get(4..4 + len)?;
+            Some((prefix.to_vec(), body[4 + len..].to_vec()))
+        }
+
+        // Where a segment that can't be replayed is kept. Names of earlier
+        // ones are never reused.
+        fn discarded_path(path: &Path) -> PathBuf {
+            let mut discarded = path.with_extension("wal.discarded");
+            let mut n = 0;
+            while discarded.exists() {
+                n += 1;
+                discarded = path.with_extension(format!("wal.discarded.{}", n));
+            }
+            discarded
+        }
+
+        pub fn read_wal(dir: &Path) -> WalLog {
+            let mut log = WalLog {
+                txs: HashMap::new(),
+                writes: Vec::new(),
+                next_tx_id: 1,
+                checkpoint: 0,
+                segment: 1,
+                discarded_bytes: 0,
+            };
+            let mut segments = wal_segments(dir).unwrap();
+            if segments.is_empty() {
+                segments.push(1);
+            }
+
+            let mut checkpoint_segment = segments[0];
+            let mut last = segments.len() - 1;
+            for (i, &segment) in segments.iter().enumerate() {
+                let path = wal_segment_path(dir, segment);
+                let wal = fs::read(&path).unwrap_or_default();
+                let mut offset = 0;
+                if wal.starts_with(&wal_header()) {
+                    offset = WAL_HEADER_LEN;
+                    while let Some((payload, len)) = wal_next_record(&wal[offset..]) {
+                        offset += len;
+                        let tx_id = bytes_to_u64(&payload[1..9]);
+                        log.next_tx_id = log.next_tx_id.max(tx_id + 1);
+                        match payload[0] {
+                            WAL_STATE => {
+                                if let Some(state) = TxState::from_wal(&payload[9..]) {
+                                    log.txs.insert(tx_id, state);
+                                }
+                            }
+                            WAL_WRITE => {
+                                if let Some((prefix, value)) = decode_write(&payload[9..]) {
+                                    log.writes.push((tx_id, prefix, value));
+                                }
+                            }
+                            WAL_CHECKPOINT => {
+                                // Everything before it is durable in RocksDB
+                                log.txs = decode_checkpoint(&payload[9..]);
+                                log.writes.clear();
+                                log.checkpoint = tx_id;
+                                checkpoint_segment = segment;
+                            }
+                            _ => {}
+                        }
+                    }
+                } else if !wal_header().starts_with(&wal) {
+                    panic!("Unrecognized WAL format.");
+                }
+
+                // Everything after the first bad record is a torn write from
+                // a crash, so drop it before anything new is appended. A bad
+                // record before the last segment is corruption instead, and
+                // the segments after it may hold committed transactions, so
+                // they are set aside rather than deleted.
+                if offset < wal.len() {
+                    log.discarded_bytes += (wal.len() - offset) as u64;
+                    if i + 1 < segments.len() {
+                        fs::copy(&path, discarded_path(&path)).unwrap();
+                    }
+                    for &later in segments[i + 1..].iter() {
+                        let later = wal_segment_path(dir, later);
+                        log.discarded_bytes += fs::metadata(&later).unwrap().len();
+                        fs::rename(&later, discarded_path(&later)).unwrap();
+                    }
+                    let file = OpenOptions::new().write(true).open(&path).unwrap();
+                    file.set_len(offset as u64).unwrap();
+                    last = i;
+                    break;
+                }
+            }
+            if log.discarded_bytes > 0 {
+                eprintln!(
+                    "Discarding {} bytes of corrupt WAL records. Segments that \
+                     were not replayed are kept as *.wal.discarded in {}.",
+                    log.discarded_bytes,
+                    dir.display()
+                );
+            }
+            for id in log.txs.keys() {
+                log.next_tx_id = log.next_tx_id.max(id + 1);
+            }
+
+            // We may have crashed before deleting the segments a checkpoint
+            // made obsolete
+            for &segment in segments[..last].iter() {
+                if segment < checkpoint_segment {
+                    fs::remove_file(wal_segment_path(dir, segment)).unwrap();
+                }
+            }
+            log.segment = segments[last];
+            log
+        }
+
+        pub fn replay(db: &DB, wal: &File, log: &mut WalLog) {
+            // Anything still pending was cut off by a crash and can never
+            // commit, so record it as aborted. States the WAL has seen since
+            // the last checkpoint may not have reached RocksDB yet.
+            for (tx_id, state) in log.txs.iter_mut() {
+                if *state == TxState::Pending {
+                    *state = TxState::Aborted;
+                    wal_write_record(wal, &wal_record(WAL_STATE, *tx_id, &state.to_wal())).unwrap();
+                }
+                persist_tx_state(db, *tx_id, *state);
+            }
+
+            // Only versioned keys go through the WAL. Meta keys such as the
+            // tx id counter are written straight to RocksDB.
+            for (tx_id, prefix, value) in log.writes.iter() {
+                match decode_prefix(prefix) {
+                    Some((KV_TAG, _)) | Some((ROW_TAG, _)) | Some((INDEX_TAG, _)) => {}
+                    _ => {
+                        eprintln!("Skipping WAL write of tx {} to an unknown key.", tx_id);
+                        continue;
+                    }
+                }
+                let key = encode_key(prefix, *tx_id);
+                match log.txs.get(tx_id) {
+                    Some(TxState::Committed(_)) => db
+                        .put(key, value)
+                        .and_then(|_| index_expiry(db, prefix, value)),
+                    _ => db.delete(key),
+                }
+                .expect("Failed to replay WAL write.");
+            }
+        }
+    }
+
+    // Writes a new version of the key identified by `prefix`, with a value
+    // encoded by `encode_value` or one of its siblings.
+    fn persist_entry(
+        db: &DBState,
+        prefix: &[u8],
+        value: &[u8],
+        tx: &WalTx,
+    ) -> Result<(), rocksdb::Error> {
+        db.db.put(encode_key(prefix, tx.id), value)?;
+        index_expiry(&db.db, prefix, value)
+    }
+
+    fn kv_prefix(key: impl AsRef<[u8]>) -> Vec<u8> {
+        encode_prefix(KV_TAG, &[key.as_ref()])
+    }
+
+    // The plain key an encoded prefix belongs to, if it is a KV key.
+    fn kv_name(prefix: &[u8]) -> Option<Vec<u8>> {
+        match decode_prefix(prefix) {
+            Some((KV_TAG, mut components)) => Some(components.remove(0)),
+            _ => None,
+        }
+    }
+
+    // Keys and values set through `set_bytes` can hold any bytes, so the
+    // string API has to check them rather than rewrite what it can't show.
+    fn to_utf8(v: Vec<u8>) -> Result<String, String> {
+        String::from_utf8(v).map_err(|e| {
+            format!(
+                "Not valid UTF-8, use get_bytes: {}",
+                String::from_utf8_lossy(e.as_bytes())
+            )
+        })
+    }
+
+    // A scanned key and its value
+    fn kv_item(prefix: &[u8], value: Vec<u8>) -> Result<(String, String), String> {
+        let key = kv_name(prefix).ok_or_else(|| String::from("Not a KV key"))?;
+        Ok((to_utf8(key)?, to_utf8(value)?))
+    }
+
+    fn row_prefix(table: &str, id: u64) -> Vec<u8> {
+        encode_prefix(ROW_TAG, &[table.as_bytes(), &id.to_be_bytes()])
+    }
+
+    fn row_col_prefix(table: &str, id: u64, col: &str) -> Vec<u8> {
+        encode_prefix(
+            ROW_TAG,
+            &[table.as_bytes(), &id.to_be_bytes(), col.as_bytes()],
+        )
+    }
+
+    // Every row holding a value gets its own index entry, so the entries
+    // for a value all start with this prefix
+    fn index_prefix(table: &str, col: &str, val: &str) -> Vec<u8> {
+        encode_prefix(
+            INDEX_TAG,
+            &[table.as_bytes(), col.as_bytes(), val.as_bytes()],
+        )
+    }
+
+    fn index_entry(table: &str, col: &str, val: &str, id: u64) -> Vec<u8> {
+        encode_prefix(
+            INDEX_TAG,
+            &[
+                table.as_bytes(),
+                col.as_bytes(),
+                val.as_bytes(),
+                &id.to_be_bytes(),
+            ],
+        )
+    }
+
+    // Only for bytes that were written from a String, such as row columns
+    // and meta values
+    fn bytes_to_string(v: &[u8]) -> String {
+        String::from_utf8_lossy(v).into_owned()
+    }
+
+    pub fn get_mutex<'a>(
+        locks: &'a mut HashMap<String, Mutex<bool>>,
+        key: &str,
+    ) -> &'a Mutex<bool> {
+        if !locks.contains_key(key) {
+            let mutex = Mutex::new(true);
+            locks.insert(key.to_string(), mutex);
+        }
+
+        locks.get(key).unwrap()
+    }
+
+    // Wraps a WalTx with the writes it has made so far. Writes are buffered
+    // in memory, keyed by their encoded key without a tx id, and only
+    // persisted when the transaction commits, so a rollback leaves nothing
+    // behind. Reads made through the transaction see its own writes.
+    pub struct Transaction {
+        tx: WalTx,
+        writes: BTreeMap<Vec<u8>, Option<Vec<u8>>>,
+        // Increments of keys the transaction hasn't written, see `incr`
+        deltas: BTreeMap<Vec<u8>, i64>,
+        // When writes made with a TTL expire
+        expires: HashMap<Vec<u8>, u64>,
+        isolation: Isolation,
+        // Everything a serializable transaction read, along with the
+        // concurrent transactions it has rw-antidependencies with. Edges in
+        // come from transactions that read something this one writes, edges
+        // out go to transactions that wrote something this one read.
+        reads: Vec<KeyRange>,
+        in_conflicts: HashSet<u64>,
+        out_conflicts: HashSet<u64>,
+        // When an open transaction was last used, see `abort_idle`
+        last_used: u64,
+    }
+
+    #[derive(Clone, Copy, Debug, PartialEq)]
+    pub enum Isolation {
+        Snapshot,
+        // Snapshot isolation plus tracking of read sets, so that
+        // transactions that could form a cycle of rw-antidependencies (e.g.
+        // write skew) are aborted on commit.
+        Serializable,
+    }
+
+    // Half-open range of encoded keys, without tx ids
+    type KeyRange = (Vec<u8>, Vec<u8>);
+
+    impl Transaction {
+        fn new(db: &mut DBState, isolation: Isolation) -> Transaction {
+            Transaction {
+                tx: wal_new_tx(db),
+                writes: BTreeMap::new(),
+                deltas: BTreeMap::new(),
+                expires: HashMap::new(),
+                isolation,
+                reads: Vec::new(),
+                in_conflicts: HashSet::new(),
+                out_conflicts: HashSet::new(),
+                last_used: now_millis(),
+            }
+        }
+
+        // A transaction that only reads from the snapshot and is never
+        // committed
+        fn read_only(snapshot: Snapshot) -> Transaction {
+            Transaction {
+                tx: snapshot.tx,
+                writes: BTreeMap::new(),
+                deltas: BTreeMap::new(),
+                expires: HashMap::new(),
+                isolation: Isolation::Snapshot,
+                reads: Vec::new(),
+                in_conflicts: HashSet::new(),
+                out_conflicts: HashSet::new(),
+                last_used: now_millis(),
+            }
+        }
+
+        fn write(&mut self, prefix: Vec<u8>, value: Option<&[u8]>) {
+            self.deltas.remove(&prefix);
+            self.expires.remove(&prefix);
+            self.writes.insert(prefix, value.map(|v| v.to_vec()));
+        }
+
+        // Writes a value that expires `ttl` seconds from now, if given
+        fn write_with_ttl(&mut self, prefix: Vec<u8>, value: &[u8], ttl: Option<u64>) {
+            self.write(prefix.clone(), Some(value));
+            if let Some(ttl) = ttl {
+                let expires_at = now_millis().saturating_add(ttl.saturating_mul(1000));
+                self.expires.insert(prefix, expires_at);
+            }
+        }
+
+        // Increments are kept apart so they can be written without knowing
+        // the current value, unless the transaction wrote the key itself.
+        fn add(&mut self, prefix: Vec<u8>, delta: i64) {
+            match self.writes.get_mut(&prefix) {
+                Some(value) => *value = Some(add_to_counter(value.as_deref(), delta)),
+                None => {
+                    let d = self.deltas.entry(prefix).or_insert(0);
+                    *d = d.wrapping_add(delta);
+                }
+            }
+        }
+
+        fn written_keys(&self) -> impl Iterator<Item = &Vec<u8>> {
+            self.writes.keys().chain(self.deltas.keys())
+        }
+
+        fn record_read(&mut self, start: &[u8], end: &[u8]) {
+            if self.isolation == Isolation::Serializable {
+                self.reads.push((start.to_vec(), end.to_vec()));
+            }
+        }
+    }
+
+    // What needs to be remembered about a committed serializable
+    // transaction while transactions that ran concurrently with it are open.
+    pub struct SsiRecord {
+        id: u64,
+        // Transactions with an id of at least this began after the commit
+        commit_mark: u64,
+        reads: Vec<KeyRange>,
+        has_in: bool,
+        // Only edges to transactions that committed before this one count
+        has_out: bool,
+    }
+
+    fn reads_any<'a, I>(reads: &[KeyRange], mut keys: I) -> bool
+    where
+        I: Iterator<Item = &'a Vec<u8>>,
+    {
+        keys.any(|k| reads.iter().any(|(start, end)| start <= k && k < end))
+    }
+
+    // Conflicts with aborted transactions can't lead to an anomaly
+    fn any_live(db: &DBState, ids: &HashSet<u64>) -> bool {
+        ids.iter()
+            .any(|id| tx_state(db, *id) != Some(TxState::Aborted))
+    }
+
+    // Records the rw-antidependencies between a committing serializable
+    // transaction and every transaction that ran concurrently with it, and
+    // decides whether committing would allow a non-serializable outcome.
+    //
+    // Any cycle in the serialization graph has a "pivot" with both an edge
+    // in and an edge out to a transaction that committed first, so we abort
+    // the transaction if it would become such a pivot itself, or if it would
+    // turn an already committed transaction into one. Edges out to
+    // transactions that are still running are left for them to check when
+    // they commit. This can abort transactions that would have been fine,
+    // but never lets an anomaly like write skew through.
+    fn check_serializable(db: &mut DBState, t: &mut Transaction) -> Result<(), String> {
+        // Edges out: concurrent writers of anything we read. Committed ones
+        // left versions we can't see, pending ones still buffer their writes.
+        for (start, end) in t.reads.iter() {
+            let db_iter = db
+                .db
+                .iterator(IteratorMode::From(start, Direction::Forward));
+            for (k, _) in db_iter {
+                if *k >= **end {
+                    break;
+                }
+                let write_tx_id = key_tx_id(&k);
+                if write_tx_id != t.tx.id
+                    && !is_visible(db, &t.tx, write_tx_id)
+                    && is_committed(db, write_tx_id)
+                {
+                    t.out_conflicts.insert(write_tx_id);
+                }
+            }
+            for (id, u) in db.open_txs.iter() {
+                if u.written_keys().any(|k| start <= k && k < end) {
+                    t.out_conflicts.insert(*id);
+                }
+            }
+        }
+
+        // Edges in: concurrent serializable readers of anything we write
+        for (id, u) in db.open_txs.iter() {
+            if reads_any(&u.reads, t.written_keys()) {
+                t.in_conflicts.insert(*id);
+            }
+        }
+        for c in db.ssi_committed.iter() {
+            if !is_visible(db, &t.tx, c.id) && reads_any(&c.reads, t.written_keys()) {
+                t.in_conflicts.insert(c.id);
+            }
+        }
+
+        let makes_committed_pivot = db.ssi_committed.iter().any(|c| {
+            (t.out_conflicts.contains(&c.id) && c.has_out)
+                || (t.in_conflicts.contains(&c.id) && c.has_in)
+        });
+        let has_in = any_live(db, &t.in_conflicts);
+        let has_out = t.out_conflicts.iter().any(|id| is_committed(db, *id));
+        if (has_in && has_out) || makes_committed_pivot {
+            return Err(format!(
+                "Serialization failure: transaction {} could not be serialized",
+                t.tx.id
+            ));
+        }
+
+        for (id, u) in db.open_txs.iter_mut() {
+            if t.out_conflicts.contains(id) {
+                u.in_conflicts.insert(t.tx.id);
+            }
+            if t.in_conflicts.contains(id) {
+                u.out_conflicts.insert(t.tx.id);
+            }
+        }
+        for c in db.ssi_committed.iter_mut() {
+            c.has_in |= t.out_conflicts.contains(&c.id);
+        }
+        let record = SsiRecord {
+            id: t.tx.id,
+            commit_mark: db.next_tx_id,
+            reads: std::mem::take(&mut t.reads),
+            has_in,
+            has_out,
+        };
+        db.ssi_committed.push(record);
+        Ok(())
+    }
+
+    // Forgets committed serializable transactions once every transaction
+    // that ran concurrently with them has finished.
+    fn prune_ssi(db: &mut DBState) {
+        let oldest_open = db.open_txs.keys().min().cloned();
+        db.ssi_committed.retain(|c| match oldest_open {
+            Some(id) => id < c.commit_mark,
+            None => false,
+        });
+    }
+
+    // Persists the buffered writes of a transaction and commits it. If any
+    // write conflicts with a concurrent transaction or fails the transaction
+    // is aborted instead.
+    fn finish(db: &mut DBState, mut t: Transaction) -> Result<(), String> {
+        if t.writes
+            .keys()
+            .any(|prefix| has_write_conflict(db, &t, prefix))
+        {
+            wal_abort(db, &t.tx, false).unwrap();
+            return Err(format!(
+                "Serialization failure: transaction {} conflicts with a concurrent write",
+                t.tx.id
+            ));
+        }
+        if t.isolation == Isolation::Serializable {
+            if let Err(e) = check_serializable(db, &mut t) {
+                wal_abort(db, &t.tx, false).unwrap();
+                prune_ssi(db);
+                return Err(e);
+            }
+            prune_ssi(db);
+        }
+        for (prefix, value) in t.writes.iter() {
+            let value = match (value, t.expires.get(prefix)) {
+                (Some(v), Some(expires_at)) => encode_expiring(v, *expires_at),
+                _ => encode_value(value.as_deref()),
+            };
+            wal_append_write(db, &t.tx, prefix, &value).unwrap();
+            if persist_entry(db, prefix, &value, &t.tx).is_err() {
+                wal_abort(db, &t.tx, true).unwrap();
+                return Err(String::from("Failed to write"));
+            }
+        }
+        for (prefix, delta) in t.deltas.iter() {
+            let delta =
+                fold_increment(db, &t, prefix, *delta).unwrap_or_else(|| encode_delta(*delta));
+            wal_append_write(db, &t.tx, prefix, &delta).unwrap();
+            if persist_entry(db, prefix, &delta, &t.tx).is_err() {
+                wal_abort(db, &t.tx, true).unwrap();
+                return Err(String::from("Failed to write"));
+            }
+        }
+        wal_commit(db, &t.tx).unwrap();
+
+        let mut cache = db.cache.lock().unwrap();
+        // Keys that aren't valid UTF-8 can't be looked up through the cache
+        let cache_key = |prefix: &[u8]| kv_name(prefix).and_then(|k| String::from_utf8(k).ok());
+        for (prefix, value) in t.writes.iter() {
+            if let Some(key) = cache_key(prefix) {
+                let value = value.clone().map(String::from_utf8);
+                match value {
+                    Some(Ok(v)) if !t.expires.contains_key(prefix) => cache.insert(key, v),
+                    _ => cache.remove(&key),
+                };
+            }
+        }
+        for prefix in t.deltas.keys() {
+            if let Some(key) = cache_key(prefix) {
+                cache.remove(&key);
+            }
+        }
+        Ok(())
+    }
+
+    // The full value an increment can be written as instead of a delta: the
+    // newest committed value plus the increment, keeping its expiry. That
+    // keeps reads of a hot counter down to one version. It's only safe if
+    // the increment would be the newest version of the key, and no open
+    // transaction that started earlier has an increment of the key to
+    // commit below it, which the full value would hide.
+    fn fold_increment(db: &DBState, t: &Transaction, prefix: &[u8], delta: i64) -> Option<Vec<u8>> {
+        let earlier_delta = db
+            .open_txs
+            .values()
+            .any(|u| u.tx.id < t.tx.id && u.deltas.contains_key(prefix));
+        if earlier_delta {
+            return None;
+        }
+        let end = prefix_end(prefix);
+        let mut versions = db
+            .db
+            .iterator(IteratorMode::From(&end, Direction::Reverse))
+            .take_while(|(k, _)| k.starts_with(prefix))
+            .map(|(k, value)| (key_tx_id(&k), value.to_vec()))
+            .filter(|(tx_id, _)| is_committed(db, *tx_id))
+            .peekable();
+        if matches!(versions.peek(), Some((tx_id, _)) if *tx_id > t.tx.id) {
+            return None;
+        }
+        let mut total = delta;
+        for (_, value) in versions {
+            if let Some(d) = decode_delta(&value) {
+                total = total.wrapping_add(d);
+                continue;
+            }
+            // Deltas on top of an expired value expired with it
+            let folded = match value_expiry(&value) {
+                Some(_) if has_lapsed(&value) => encode_value(Some(&add_to_counter(None, delta))),
+                Some(expires_at) => {
+                    encode_expiring(&add_to_counter(decode_value(&value), total), expires_at)
+                }
+                None => encode_value(Some(&add_to_counter(decode_value(&value), total))),
+            };
+            return Some(folded);
+        }
+        Some(encode_value(Some(&add_to_counter(None, total))))
+    }
+
+    // First committer wins: a transaction may not write a key that another
+    // transaction has committed a version of since this one began. Those are
+    // exactly the committed versions the transaction can't see, i.e. ones
+    // from later transactions or from ones that were still in progress when
+    // it started, so anything older than all of those can be skipped.
+    //
+    // Increments add to whatever value is newest when they are read, so they
+    // don't conflict with anything and aren't checked.
+    fn has_write_conflict(db: &DBState, t: &Transaction, prefix: &[u8]) -> bool {
+        let horizon = *t.tx.in_progress.iter().min().unwrap_or(&t.tx.id);
+        let end = prefix_end(prefix);
+        let db_iter = db.db.iterator(IteratorMode::From(&end, Direction::Reverse));
+        for (k, _) in db_iter {
+            if !k.starts_with(prefix) {
+                break;
+            }
+            let write_tx_id = key_tx_id(&k);
+            if write_tx_id < horizon {
+                break;
+            }
+            if !is_visible(db, &t.tx, write_tx_id) && is_committed(db, write_tx_id) {
+                return true;
+            }
+        }
+        false
+    }
+
+    // A read-only view of everything committed when it was taken. Unlike a
+    // transaction it gets no tx id and writes nothing to the WAL, it only
+    // remembers the high-water tx id and which transactions were pending.
+    pub struct Snapshot {
+        tx: WalTx,
+    }
+
+    impl Snapshot {
+        pub fn new(db: &DBState) -> Snapshot {
+            Snapshot {
+                tx: WalTx {
+                    id: db.next_tx_id,
+                    in_progress: db.txs.keys().cloned().collect(),
+                },
+            }
+        }
+
+        // The db as it was once tx `tx_id` and everything before it had
+        // finished, as far as commits are concerned
+        pub fn as_of(tx_id: u64) -> Snapshot {
+            Snapshot {
+                tx: WalTx {
+                    id: tx_id.saturating_add(1),
+                    in_progress: HashSet::new(),
+                },
+            }
+        }
+    }
+
+    // Runs the read-only `f` against a fresh snapshot
+    fn with_snapshot<T, F>(db: &DBState, f: F) -> T
+    where
+        F: FnOnce(&DBState, &mut Transaction) -> T,
+    {
+        let mut t = Transaction::read_only(Snapshot::new(db));
+        f(db, &mut t)
+    }
+
+    // Runs the read-only `f` against the db as of tx `tx_id`. Vacuum only
+    // keeps the versions visible to snapshots from its horizon on, so older
+    // reads are refused rather than answered wrongly.
+    fn with_snapshot_as_of<T, F>(db: &DBState, tx_id: u64, f: F) -> Result<T, String>
+    where
+        F: FnOnce(&DBState, &mut Transaction) -> T,
+    {
+        let next = tx_id
+            .checked_add(1)
+            .ok_or_else(|| format!("Invalid as_of: {}", tx_id))?;
+        if next < db.vacuumed_below {
+            return Err(format!(
+                "History before transaction {} has been vacuumed",
+                db.vacuumed_below
+            ));
+        }
+        let mut t = Transaction::read_only(Snapshot::as_of(tx_id));
+        Ok(f(db, &mut t))
+    }
+
+    // Runs `f` in a fresh transaction, committing it if `f` succeeds and
+    // aborting it otherwise.
+    fn autocommit<T, F>(db: &mut DBState, f: F) -> Result<T, String>
+    where
+        F: FnOnce(&mut DBState, &mut Transaction) -> Result<T, String>,
+    {
+        let mut t = Transaction::new(db, Isolation::Snapshot);
+        match f(db, &mut t) {
+            Ok(res) => finish(db, t).map(|_| res),
+            Err(e) => {
+                wal_abort(db, &t.tx, false).unwrap();
+                Err(e)
+            }
+        }
+    }
+
+    // Runs `f` inside the open transaction with the given handle. The
+    // transaction is taken out of `open_txs` while `f` runs so that both can
+    // be borrowed mutably.
+    fn with_open_tx<T, F>(db: &mut DBState, tx_id: u64, f: F) -> Result<T, String>
+    where
+        F: FnOnce(&mut DBState, &mut Transaction) -> Result<T, String>,
+    {
+        let mut t = db
+            .open_txs
+            .remove(&tx_id)
+            .ok_or_else(|| format!("Unknown transaction: {}", tx_id))?;
+        let res = f(db, &mut t);
+        t.last_used = now_millis();
+        db.open_txs.insert(tx_id, t);
+        res
+    }
+
+    // Starts a snapshot isolated transaction that stays open across calls
+    // until it is committed or rolled back, and returns its handle.
+    pub fn begin(db: &mut DBState) -> u64 {
+        begin_with(db, Isolation::Snapshot)
+    }
+
+    pub fn begin_with(db: &mut DBState, isolation: Isolation) -> u64 {
+        let t = Transaction::new(db, isolation);
+        let id = t.tx.id;
+        db.open_txs.insert(id, t);
+        id
+    }
+
+    pub fn commit(db: &mut DBState, tx_id: u64) -> Result<String, String> {
+        let t = db
+            .open_txs
+            .remove(&tx_id)
+            .ok_or_else(|| format!("Unknown transaction: {}", tx_id))?;
+        finish(db, t)?;
+        Ok(format!("Committed transaction: {}", tx_id))
+    }
+
+    pub fn rollback(db: &mut DBState, tx_id: u64) -> Result<String, String> {
+        let t = db
+            .open_txs
+            .remove(&tx_id)
+            .ok_or_else(|| format!("Unknown transaction: {}", tx_id))?;
+        wal_abort(db, &t.tx, false).unwrap();
+        prune_ssi(db);
+        Ok(format!("Rolled back transaction: {}", tx_id))
+    }
+
+    // How long an open transaction can go unused before `abort_idle` rolls
+    // it back, in milliseconds
+    pub const TX_IDLE_TIMEOUT: u64 = 5 * 60 * 1000;
+
+    // Rolls back open transactions that haven't been used for `max_idle`
+    // milliseconds. A client that never commits or rolls back would
+    // otherwise hold back tx state compaction and vacuum forever. Returns
+    // the number rolled back.
+    pub fn abort_idle(db: &mut DBState, max_idle: u64) -> usize {
+        let now = now_millis();
+        let idle: Vec<u64> = db
+            .open_txs
+            .iter()
+            .filter(|(_, t)| now.saturating_sub(t.last_used) >= max_idle)
+            .map(|(id, _)| *id)
+            .collect();
+        for id in idle.iter() {
+            rollback(db, *id).unwrap();
+        }
+        idle.len()
+    }
+
+    // Newest value of an encoded key visible to the transaction, or None if
+    // the key doesn't exist or was deleted.
+    fn read_key(db: &DBState, t: &mut Transaction, prefix: &[u8]) -> Option<Vec<u8>> {
+        let end = prefix_end(prefix);
+        t.record_read(prefix, &end);
+        if t.writes.contains_key(prefix) {
+            return own_value(t, prefix, None);
+        }
+        let value = newest_version(db, t, prefix).and_then(|(_, value)| value);
+        own_value(t, prefix, value)
+    }
+
+    // A key's value once the transaction's own writes are applied to the
+    // newest committed one
+    fn own_value(t: &Transaction, prefix: &[u8], committed: Option<Vec<u8>>) -> Option<Vec<u8>> {
+        if let Some(value) = t.writes.get(prefix) {
+            return value.clone();
+        }
+        match t.deltas.get(prefix) {
+            Some(delta) => Some(add_to_counter(committed.as_deref(), *delta)),
+            None => committed,
+        }
+    }
+
+    // Tx id and value of the newest version of an encoded key visible to the
+    // transaction, ignoring its own writes. The value is None for a tombstone.
+    fn newest_version(
+        db: &DBState,
+        t: &Transaction,
+        prefix: &[u8],
+    ) -> Option<(u64, Option<Vec<u8>>)> {
+        let end = prefix_end(prefix);
+        let versions = db
+            .db
+            .iterator(IteratorMode::From(&end, Direction::Reverse))
+            .take_while(|(k, _)| k.starts_with(prefix))
+            .map(|(k, value)| (key_tx_id(&k), value.to_vec()))
+            .filter(|(tx_id, _)| is_visible(db, &t.tx, *tx_id));
+        resolve_versions(versions)
+    }
+
+    fn read_row(
+        db: &DBState,
+        t: &mut Transaction,
+        table: &str,
+        id: u64,
+    ) -> HashMap<String, String> {
+        // Start the search after the last key of the row and walk backwards
+        // through its columns.
+        let prefix = row_prefix(table, id);
+        let search_k = prefix_end(&prefix);
+        t.record_read(&prefix, &search_k);
+        let mut record = HashMap::new();
+        let mut db_iter = db.db.iterator(IteratorMode::End);
+        db_iter.set_mode(IteratorMode::From(&search_k, Direction::Reverse));
+        for (k, value) in db_iter {
+            if !k.starts_with(&prefix) {
+                break;
+            }
+            let k = match decode_key(&k) {
+                Some(k) => k,
+                None => continue,
+            };
+            let col = bytes_to_string(&k.components[2]);
+
+            // We insert the newest value for a col first, so we should not
+            // overwrite any existing col entries
+            if !record.contains_key(&col) && is_visible(db, &t.tx, k.tx_id) {
+                if let Some(v) = decode_value(&value) {
+                    record.insert(col, bytes_to_string(v));
+                }
+            }
+        }
+        for (key, value) in t.writes.range(prefix.clone()..search_k) {
+            if let (Some(key), Some(v)) = (decode_prefix(key), value) {
+                record.insert(bytes_to_string(&key.1[2]), bytes_to_string(v));
+            }
+        }
+        record
+    }
+
+    // Writes the given columns and moves their index entries from the old
+    // values to the new ones
+    fn write_row(
+        db: &DBState,
+        t: &mut Transaction,
+        table: &str,
+        id: u64,
+        colvals: &HashMap<String, String>,
+    ) {
+        for (col, value) in colvals.iter() {
+            let prefix = row_col_prefix(table, id, col);
+            match read_key(db, t, &prefix) {
+                Some(old) if old == value.as_bytes() => continue,
+                Some(old) => {
+                    let old = bytes_to_string(&old);
+                    t.write(index_entry(table, col, &old, id), None);
+                }
+                None => {}
+            }
+            t.write(prefix, Some(value.as_bytes()));
+            insert_secondary_index(t, table, col, value, id);
+        }
+    }
+
+    fn write_new_row(
+        db: &mut DBState,
+        t: &mut Transaction,
+        table: &str,
+        colvals: &HashMap<String, String>,
+    ) -> u64 {
+        let id = table_next_id(db, table);
+        // The id is indexed like any other column so rows can be looked up
+        // through get_by_col as well.
+        insert_secondary_index(t, table, "id", &id.to_string(), id);
+        write_row(db, t, table, id, colvals);
+        id
+    }
+
+    pub fn set(db: &mut DBState, key: String, value: String) -> Result<String, String> {
+        set_with_ttl(db, key, value, None)
+    }
+
+    // Sets a key that reads as deleted once `ttl` seconds have passed
+    pub fn set_with_ttl(
+        db: &mut DBState,
+        key: String,
+        value: String,
+        ttl: Option<u64>,
+    ) -> Result<String, String> {
+        set_bytes(db, key.as_bytes(), value.as_bytes(), ttl)
+            .map(|_| format!("Set key: {} to value: {}", key, value))
+    }
+
+    // `get`, `set_with_ttl` and `delete` for keys and values that are
+    // arbitrary bytes. The string versions refuse to read anything that isn't
+    // valid UTF-8.
+    pub fn set_bytes(
+        db: &mut DBState,
+        key: &[u8],
+        value: &[u8],
+        ttl: Option<u64>,
+    ) -> Result<(), String> {
+        autocommit(db, |_, t| {
+            t.write_with_ttl(kv_prefix(key), value, ttl);
+            Ok(())
+        })
+    }
+
+    pub fn get_bytes(db: &DBState, key: &[u8]) -> Result<Vec<u8>, String> {
+        with_snapshot(db, |db, t| {
+            read_key(db, t, &kv_prefix(key)).ok_or_else(|| String::from("Not found!"))
+        })
+    }
+
+    pub fn delete_bytes(db: &mut DBState, key: &[u8]) -> Result<(), String> {
+        // Deletes are just another version of the key, so they go through the
+        // WAL exactly like a set and only take effect once committed.
+        autocommit(db, |_, t| {
+            t.write(kv_prefix(key), None);
+            Ok(())
+        })
+        .map_err(|_| String::from("Failed to delete"))
+    }
+
+    pub fn multi_set(db: &mut DBState, keyvals: HashMap<String, String>) -> Result<String, String> {
+        multi_set_with_ttl(db, keyvals, None)
+    }
+
+    pub fn multi_set_with_ttl(
+        db: &mut DBState,
+        keyvals: HashMap<String, String>,
+        ttl: Option<u64>,
+    ) -> Result<String, String> {
+        autocommit(db, |_, t| {
+            let mut result_str = "".to_string();
+            for (key, value) in keyvals.iter() {
+                t.write_with_ttl(kv_prefix(key), value.as_bytes(), ttl);
+                let partial_result = format!("Set key: {} to value: {};", key, value);
+                result_str.push_str(&partial_result);
+            }
+            Ok(result_str)
+        })
+    }
+
+    // Conditional writes check the latest committed version from inside their
+    // own transaction, so a concurrent write that slips in before they commit
+    // still makes them fail with a serialization failure.
+    const CONFLICT: &str = "Conflict";
+
+    pub fn is_conflict(err: &str) -> bool {
+        err.starts_with(CONFLICT) || err.starts_with("Serialization failure")
+    }
+
+    // Errors from requests that can't succeed as they are, such as reading
+    // from before the vacuum horizon
+    pub fn is_invalid(err: &str) -> bool {
+        err.starts_with("Invalid") || err.starts_with("History before")
+    }
+
+    pub fn is_not_found(err: &str) -> bool {
+        err == "Not found!" || err.starts_with("Unknown transaction")
+    }
+
+    pub fn compare_and_set(
+        db: &mut DBState,
+        key: String,
+        expected: String,
+        value: String,
+    ) -> Result<String, String> {
+        autocommit(db, |db, t| {
+            let prefix = kv_prefix(&key);
+            if read_key(db, t, &prefix).as_deref() != Some(expected.as_bytes()) {
+                return Err(format!(
+                    "{}: key {} does not have value {}",
+                    CONFLICT, key, expected
+                ));
+            }
+            t.write(prefix, Some(value.as_bytes()));
+            Ok(format!("Set key: {} to value: {}", key, value))
+        })
+    }
+
+    pub fn set_if_absent(db: &mut DBState, key: String, value: String) -> Result<String, String> {
+        autocommit(db, |db, t| {
+            let prefix = kv_prefix(&key);
+            if read_key(db, t, &prefix).is_some() {
+                return Err(format!("{}: key {} already exists", CONFLICT, key));
+            }
+            t.write(prefix, Some(value.as_bytes()));
+            Ok(format!("Set key: {} to value: {}", key, value))
+        })
+    }
+
+    // Sets the key only if its newest committed version, which may be a
+    // delete, was written by transaction `tx_id`.
+    pub fn set_if_version(
+        db: &mut DBState,
+        key: String,
+        tx_id: u64,
+        value: String,
+    ) -> Result<String, String> {
+        autocommit(db, |db, t| {
+            let prefix = kv_prefix(&key);
+            t.record_read(&prefix, &prefix_end(&prefix));
+            match newest_version(db, t, &prefix) {
+                Some((version, _)) if version == tx_id => {}
+                Some((version, _)) => {
+                    return Err(format!(
+                        "{}: key {} was last written by transaction {}",
+                        CONFLICT, key, version
+                    ))
+                }
+                None => return Err(format!("{}: key {} does not exist", CONFLICT, key)),
+            }
+            t.write(prefix, Some(value.as_bytes()));
+            Ok(format!("Set key: {} to value: {}", key, value))
+        })
+    }
+
+    // Adds `delta` to the number stored at `key` and returns the new value.
+    // The increment is kept as a delta until the transaction commits, so
+    // concurrent increments never conflict. It is then added to the newest
+    // value, see `fold_increment`, or written as a delta that readers add up
+    // until vacuum folds it. A key that doesn't exist counts as 0.
+    pub fn incr(db: &mut DBState, key: String, delta: i64) -> Result<String, String> {
+        autocommit(db, |_, t| {
+            t.add(kv_prefix(&key), delta);
+            Ok(())
+        })?;
+        get(db, key)
+    }
+
+    pub fn decr(db: &mut DBState, key: String, delta: i64) -> Result<String, String> {
+        incr(db, key, delta.wrapping_neg())
+    }
+
+    pub fn tx_incr(
+        db: &mut DBState,
+        tx_id: u64,
+        key: String,
+        delta: i64,
+    ) -> Result<String, String> {
+        with_open_tx(db, tx_id, |_, t| {
+            t.add(kv_prefix(&key), delta);
+            Ok(format!("Incremented key: {} by {}", key, delta))
+        })
+    }
+
+    pub fn delete(db: &mut DBState, key: String) -> Result<String, String> {
+        delete_bytes(db, key.as_bytes()).map(|_| format!("Deleted key: {}", key))
+    }
+
+    pub fn get(db: &DBState, key: String) -> Result<String, String> {
+        get_bytes(db, key.as_bytes()).and_then(to_utf8)
+    }
+
+    // Reads all of `keys` from the same snapshot. Missing keys map to None
+    // rather than failing the whole batch. Each key needs a seek to find its
+    // newest visible version, so RocksDB's multi-get can't be used here.
+    pub fn multi_get(
+        db: &DBState,
+        keys: Vec<String>,
+    ) -> Result<Vec<(String, Option<String>)>, String> {
+        with_snapshot(db, |db, t| {
+            keys.into_iter()
+                .map(|key| {
+                    let value = read_key(db, t, &kv_prefix(&key)).map(to_utf8).transpose()?;
+                    Ok((key, value))
+                })
+                .collect()
+        })
+    }
+
+    // Values of every key from `start` to `end` inclusive
+    pub fn scan(db: &DBState, start: String, end: String) -> Result<Vec<String>, String> {
+        let opts = ScanOptions {
+            start: Bound::Included(start),
+            end: Bound::Included(end),
+            ..ScanOptions::default()
+        };
+        let page = scan_range(db, &opts)?;
+        Ok(page.items.into_iter().map(|(_, value)| value).collect())
+    }
+
+    pub struct ScanOptions {
+        pub start: Bound<String>,
+        pub end: Bound<String>,
+        pub reverse: bool,
+        pub limit: Option<usize>,
+        // Where the previous page left off
+        pub cursor: Option<String>,
+        pub as_of: Option<u64>,
+        // Only keys starting with this are returned
+        pub prefix: Option<String>,
+    }
+
+    impl Default for ScanOptions {
+        fn default() -> ScanOptions {
+            ScanOptions {
+                start: Bound::Unbounded,
+                end: Bound::Unbounded,
+                reverse: false,
+                limit: None,
+                cursor: None,
+                as_of: None,
+                prefix: None,
+            }
+        }
+    }
+
+    #[derive(Debug)]
+    pub struct ScanPage {
+        pub items: Vec<(String, String)>,
+        // Pass back as `ScanOptions::cursor` to get the next page. None once
+        // the range has been exhausted.
+        pub cursor: Option<String>,
+    }
+
+    // Cursors are the hex encoded prefix of the last key returned
+    fn encode_cursor(prefix: &[u8]) -> String {
+        prefix.iter().map(|b| format!("{:02x}", b)).collect()
+    }
+
+    fn decode_cursor(cursor: &str) -> Result<Vec<u8>, String> {
+        let prefix: Option<Vec<u8>> = cursor
+            .as_bytes()
+            .chunks(2)
+            .map(|pair| match std::str::from_utf8(pair) {
+                Ok(pair) if pair.len() == 2 => u8::from_str_radix(pair, 16).ok(),
+                _ => None,
+            })
+            .collect();
+        match prefix {
+            Some(prefix) if kv_name(&prefix).is_some() => Ok(prefix),
+            _ => Err(format!("Invalid cursor: {}", cursor)),
+        }
+    }
+
+    // Key/value pairs in a range of keys, a page at a time. Only as many
+    // versions as are needed to fill the page are read.
+    pub fn scan_range(db: &DBState, opts: &ScanOptions) -> Result<ScanPage, String> {
+        let (start, end) = scan_bounds(opts)?;
+        let read = |db: &DBState, t: &mut Transaction| read_scan(db, t, opts, &start, &end);
+        let page = match opts.as_of {
+            Some(tx_id) => with_snapshot_as_of(db, tx_id, read)?,
+            None => with_snapshot(db, read),
+        };
+        to_scan_page(page)
+    }
+
+    // Like `scan_range`, but inside an open transaction, which sees its own
+    // writes. `as_of` is ignored.
+    pub fn tx_scan_range(
+        db: &mut DBState,
+        tx_id: u64,
+        opts: &ScanOptions,
+    ) -> Result<ScanPage, String> {
+        let (start, end) = scan_bounds(opts)?;
+        let page = with_open_tx(db, tx_id, |db, t| Ok(read_scan(db, t, opts, &start, &end)))?;
+        to_scan_page(page)
+    }
+
+    // The half open range of encoded keys left to scan
+    fn scan_bounds(opts: &ScanOptions) -> Result<(Vec<u8>, Vec<u8>), String> {
+        if opts.limit == Some(0) {
+            return Err(String::from("Invalid limit: 0"));
+        }
+        // Encoded keys sort like the raw keys, so the bounds become a half
+        // open range of encoded keys
+        let mut start = match &opts.start {
+            Bound::Included(key) => kv_prefix(key),
+            Bound::Excluded(key) => prefix_end(&kv_prefix(key)),
+            Bound::Unbounded => vec![KV_TAG],
+        };
+        let mut end = match &opts.end {
+            Bound::Included(key) => prefix_end(&kv_prefix(key)),
+            Bound::Excluded(key) => kv_prefix(key),
+            Bound::Unbounded => vec![KV_TAG + 1],
+        };
+        if let Some(prefix) = &opts.prefix {
+            let prefix = encode_key_prefix(prefix);
+            end = end.min(prefix_successor(&prefix));
+            start = start.max(prefix);
+        }
+        if let Some(cursor) = &opts.cursor {
+            let last = decode_cursor(cursor)?;
+            if opts.reverse {
+                end = end.min(last);
+            } else {
+                start = start.max(prefix_end(&last));
+            }
+        }
+        Ok((start, end))
+    }
+
+    fn read_scan(
+        db: &DBState,
+        t: &mut Transaction,
+        opts: &ScanOptions,
+        start: &[u8],
+        end: &[u8],
+    ) -> RawPage {
+        let mode = if opts.reverse {
+            IteratorMode::From(end, Direction::Reverse)
+        } else {
+            IteratorMode::From(start, Direction::Forward)
+        };
+        let db_iter = db.db.iterator(mode);
+        read_page(db, t, db_iter, start, end, opts.reverse, opts.limit)
+    }
+
+    fn to_scan_page((items, last): RawPage) -> Result<ScanPage, String> {
+        Ok(ScanPage {
+            items: items
+                .into_iter()
+                .map(|(prefix, value)| kv_item(&prefix, value))
+                .collect::<Result<_, _>>()?,
+            cursor: last.map(|prefix| encode_cursor(&prefix)),
+        })
+    }
+
+    // Encoded keys and values found by a scan, along with the key to resume
+    // from if there are more
+    type RawPage = (Vec<(Vec<u8>, Vec<u8>)>, Option<Vec<u8>>);
+
+    // Walks `db_iter`, which must already be positioned at `start` (or `end`
+    // when reversed), collecting the newest visible value of each key along
+    // with the transaction's own writes.
+    fn read_page<I>(
+        db: &DBState,
+        t: &mut Transaction,
+        db_iter: I,
+        start: &[u8],
+        end: &[u8],
+        reverse: bool,
+        limit: Option<usize>,
+    ) -> RawPage
+    where
+        I: Iterator<Item = (Box<[u8]>, Box<[u8]>)>,
+    {
+        t.record_read(start, end);
+        let mut page = PageBuilder::new(t, start, end, reverse, limit);
+        // The key being looked at and its visible versions so far
+        let mut prefix: Vec<u8> = Vec::new();
+        let mut visible: Vec<(u64, Vec<u8>)> = Vec::new();
+        for (k, value) in db_iter {
+            if *k >= *end {
+                if reverse {
+                    continue;
+                }
+                break;
+            }
+            if *k < *start {
+                break;
+            }
+
+            let key_prefix = &k[..k.len() - TX_ID_LEN];
+            if key_prefix != &prefix[..] {
+                if !prefix.is_empty() {
+                    page.add_committed(&prefix, newest_visible(std::mem::take(&mut visible)));
+                }
+                if page.more {
+                    break;
+                }
+                prefix = key_prefix.to_vec();
+            }
+
+            let tx_id = key_tx_id(&k);
+            if is_visible(db, &t.tx, tx_id) {
+                visible.push((tx_id, value.to_vec()));
+            }
+        }
+        if !prefix.is_empty() && !page.more {
+            page.add_committed(&prefix, newest_visible(visible));
+        }
+        page.finish()
+    }
+
+    // The value the newest of a key's visible versions resolves to, None if
+    // it was deleted or there aren't any
+    fn newest_visible(mut visible: Vec<(u64, Vec<u8>)>) -> Option<Vec<u8>> {
+        visible.sort_by_key(|(tx_id, _)| Reverse(*tx_id));
+        resolve_versions(visible.into_iter()).and_then(|(_, value)| value)
+    }
+
+    // Merges the keys of a scan found on disk with those the transaction
+    // wrote itself, in scan order. Deleted keys are left out. One key past
+    // the limit is looked for, so that a cursor is only handed out if there
+    // really is another page.
+    struct PageBuilder<'a> {
+        t: &'a Transaction,
+        // Keys the transaction wrote that the scan hasn't reached yet
+        own: Peekable<std::vec::IntoIter<Vec<u8>>>,
+        reverse: bool,
+        limit: usize,
+        items: Vec<(Vec<u8>, Vec<u8>)>,
+        // Whether a key past the limit was found
+        more: bool,
+    }
+
+    impl<'a> PageBuilder<'a> {
+        fn new(
+            t: &'a Transaction,
+            start: &[u8],
+            end: &[u8],
+            reverse: bool,
+            limit: Option<usize>,
+        ) -> PageBuilder<'a> {
+            let mut own: Vec<Vec<u8>> = t
+                .written_keys()
+                .filter(|k| start <= &k[..] && &k[..] < end)
+                .cloned()
+                .collect();
+            own.sort();
+            if reverse {
+                own.reverse();
+            }
+            PageBuilder {
+                t,
+                own: own.into_iter().peekable(),
+                reverse,
+                limit: limit.unwrap_or(usize::MAX),
+                items: Vec::new(),
+                more: false,
+            }
+        }
+
+        // Adds a key found on disk, given its newest committed value
+        fn add_committed(&mut self, prefix: &[u8], committed: Option<Vec<u8>>) {
+            let reverse = self.reverse;
+            while let Some(key) = self.own.next_if(|k| {
+                if reverse {
+                    &k[..] > prefix
+                } else {
+                    &k[..] < prefix
+                }
+            }) {
+                self.add_own(key);
+            }
+            self.own.next_if(|k| &k[..] == prefix);
+            let value = own_value(self.t, prefix, committed);
+            self.push(prefix.to_vec(), value);
+        }
+
+        // Adds a key that only the transaction has written
+        fn add_own(&mut self, key: Vec<u8>) {
+            let value = own_value(self.t, &key, None);
+            self.push(key, value);
+        }
+
+        fn push(&mut self, prefix: Vec<u8>, value: Option<Vec<u8>>) {
+            if let Some(value) = value {
+                if self.items.len() < self.limit {
+                    self.items.push((prefix, value));
+                } else {
+                    self.more = true;
+                }
+            }
+        }
+
+        fn finish(mut self) -> RawPage {
+            while !self.more {
+                match self.own.next() {
+                    Some(key) => self.add_own(key),
+                    None => break,
+                }
+            }
+            let cursor = if self.more {
+                self.items.last().map(|(prefix, _)| prefix.clone())
+            } else {
+                None
+            };
+            (self.items, cursor)
+        }
+    }
+
+    // All keys starting with `prefix`, in order
+    pub fn prefix_scan(db: &DBState, prefix: String) -> Result<Vec<(String, String)>, String> {
+        let opts = ScanOptions {
+            prefix: Some(prefix),
+            ..ScanOptions::default()
+        };
+        Ok(scan_range(db, &opts)?.items)
+    }
+
+    // Escaping a key never changes the bytes before a given position, so the
+    // encoded keys that start with the escaped prefix are exactly the keys
+    // starting with `prefix`. A scan seeks to the first of them and stops
+    // before `prefix_successor`, since the db has no prefix extractor to
+    // narrow the search with.
+    fn encode_key_prefix(prefix: &str) -> Vec<u8> {
+        let mut encoded = vec![KV_TAG];
+        for &b in prefix.as_bytes() {
+            encoded.push(b);
+            if b == 0x00 {
+                encoded.push(0xff);
+            }
+        }
+        encoded
+    }
+
+    // Smallest key greater than every key starting with `prefix`. Unlike
+    // `prefix_end` this works on partial components, which may end in 0xff.
+    fn prefix_successor(prefix: &[u8]) -> Vec<u8> {
+        let mut end = prefix.to_vec();
+        while let Some(b) = end.pop() {
+            if b < 0xff {
+                end.push(b + 1);
+                break;
+            }
+        }
+        end
+    }
+
+    pub fn get_as_of(db: &DBState, key: String, tx_id: u64) -> Result<String, String> {
+        with_snapshot_as_of(db, tx_id, |db, t| read_key(db, t, &kv_prefix(&key)))?
+            .ok_or_else(|| String::from("Not found!"))
+            .and_then(to_utf8)
+    }
+
+    pub fn tx_set(
+        db: &mut DBState,
+        tx_id: u64,
+        key: String,
+        value: String,
+    ) -> Result<String, String> {
+        tx_set_with_ttl(db, tx_id, key, value, None)
+    }
+
+    pub fn tx_set_with_ttl(
+        db: &mut DBState,
+        tx_id: u64,
+        key: String,
+        value: String,
+        ttl: Option<u64>,
+    ) -> Result<String, String> {
+        with_open_tx(db, tx_id, |_, t| {
+            t.write_with_ttl(kv_prefix(&key), value.as_bytes(), ttl);
+            Ok(format!("Set key: {} to value: {}", key, value))
+        })
+    }
+
+    pub fn tx_delete(db: &mut DBState, tx_id: u64, key: String) -> Result<String, String> {
+        with_open_tx(db, tx_id, |_, t| {
+            t.write(kv_prefix(&key), None);
+            Ok(format!("Deleted key: {}", key))
+        })
+    }
+
+    pub fn tx_get(db: &mut DBState, tx_id: u64, key: String) -> Result<String, String> {
+        with_open_tx(db, tx_id, |db, t| {
+            read_key(db, t, &kv_prefix(&key))
+                .ok_or_else(|| String::from("Not found!"))
+                .and_then(to_utf8)
+        })
+    }
+
+    pub fn mem_get(db: &DBState, key: String) -> Result<String, String> {
+        if let Some(value) = db.cache.lock().unwrap().get(&key) {
+            return Ok(value);
+        }
+        let (value, expires_at) = with_snapshot(db, |db, t| {
+            let prefix = kv_prefix(&key);
+            (read_key(db, t, &prefix), newest_expiry(db, t, &prefix))
+        });
+        let value = value
+            .ok_or_else(|| String::from("Not found!"))
+            .and_then(to_utf8)?;
+        // The cache has no notion of time, so values that expire skip it
+        if expires_at.is_none() {
+            db.cache.lock().unwrap().insert(key, value.clone());
+        }
+        Ok(value)
+    }
+
+    // When the value a key currently holds expires, if it has a TTL. Deltas
+    // on top of a value expire along with it.
+    fn newest_expiry(db: &DBState, t: &Transaction, prefix: &[u8]) -> Option<u64> {
+        let end = prefix_end(prefix);
+        db.db
+            .iterator(IteratorMode::From(&end, Direction::Reverse))
+            .take_while(|(k, _)| k.starts_with(prefix))
+            .filter(|(k, value)| is_visible(db, &t.tx, key_tx_id(k)) && value[0] != DELTA_TAG)
+            .map(|(_, value)| value_expiry(&value))
+            .next()?
+    }
+
+    // How many expiry index entries `expired_keys` returns at most, which
+    // bounds how long the reaper holds the db at a time
+    pub const REAP_BATCH: usize = 1000;
+
+    // Expiry index entries that are due, oldest first, to be passed to
+    // `delete_expired`. Only the due part of the index is read, so this is
+    // a short scan under a shared borrow of the db.
+    pub fn expired_keys(db: &DBState) -> Vec<Vec<u8>> {
+        let now = now_millis();
+        db.db
+            .iterator(IteratorMode::From(EXPIRY_PREFIX, Direction::Forward))
+            .take_while(|(k, _)| {
+                k.starts_with(EXPIRY_PREFIX) && bytes_to_u64(&k[EXPIRY_PREFIX.len()..][..8]) <= now
+            })
+            .take(REAP_BATCH)
+            .map(|(k, _)| k.to_vec())
+            .collect()
+    }
+
+    // Writes tombstones for the keys of the entries found by `expired_keys`,
+    // so that vacuum can reclaim them, then drops the entries. Keys may have
+    // been written again since they were found, so each one is checked
+    // again. Returns the number of keys deleted.
+    pub fn delete_expired(db: &mut DBState, entries: Vec<Vec<u8>>) -> Result<usize, String> {
+        if entries.is_empty() {
+            return Ok(0);
+        }
+        // Pending writers of these keys will conflict with the tombstones
+        let count = autocommit(db, |db, t| {
+            let now = now_millis();
+            let mut count = 0;
+            for entry in entries.iter() {
+                let prefix = &entry[EXPIRY_PREFIX.len() + 8..];
+                if !t.writes.contains_key(prefix) && has_expired(db, t, prefix, now) {
+                    t.write(prefix.to_vec(), None);
+                    count += 1;
+                }
+            }
+            Ok(count)
+        })?;
+        for entry in entries {
+            db.db.delete(entry).expect("Failed to delete expiry entry.");
+        }
+        Ok(count)
+    }
+
+    // Whether the value under any deltas has expired
+    fn has_expired(db: &DBState, t: &Transaction, prefix: &[u8], now: u64) -> bool {
+        matches!(newest_expiry(db, t, prefix), Some(expires_at) if expires_at <= now)
+    }
+
+    fn table_next_id(db: &mut DBState, table: &str) -> u64 {
+        let auto_inc_key = format!("**autoincrement**{}", table);
+        let mutex = get_mutex(&mut db.locks, &auto_inc_key);
+        let next_id = {
+            let _m = mutex.lock().unwrap();
+            let next_id = match db.db.get(auto_inc_key.as_bytes()).unwrap() {
+                Some(v) => bytes_to_string(&v),
+                None => "0".to_string(),
+            };
+            let next_id: u64 = next_id.parse().unwrap();
+            next_id
+        };
+        db.db
+            .put(
+                auto_inc_key.as_bytes(),
+                format!("{}", next_id + 1).as_bytes(),
+            )
+            .expect("Failed to write new auto inc id.");
+        next_id
+    }
+
+    fn is_committed(db: &DBState, tx_id: u64) -> bool {
+        match tx_state(db, tx_id) {
+            Some(state) => state.is_committed(),
+            _ => false,
+        }
+    }
+
+    // A version is visible to `tx` if it was written by an earlier
+    // transaction that has committed. Versions from a writer that is still
+    // pending are skipped since it may yet abort, and versions from aborted
+    // writers are never visible. Writers that were still pending when `tx`
+    // began stay invisible even if they commit later on.
+    fn is_visible(db: &DBState, tx: &WalTx, write_tx_id: u64) -> bool {
+        if write_tx_id >= tx.id || tx.in_progress.contains(&write_tx_id) {
+            return false;
+        }
+        match tx_state(db, write_tx_id) {
+            Some(TxState::Committed(_)) => true,
+            Some(TxState::Pending) | Some(TxState::Aborted) | None => false,
+        }
+    }
+
+    fn insert_secondary_index(t: &mut Transaction, table: &str, col: &str, val: &str, id: u64) {
+        t.write(index_entry(table, col, val, id), Some(&id.to_be_bytes()));
+    }
+
+    // Rows come back with their id added, so it can't be a column too
+    fn check_columns(colvals: &HashMap<String, String>) -> Result<(), String> {
+        if colvals.contains_key("id") {
+            return Err(String::from("Invalid column: id is set by the table"));
+        }
+        Ok(())
+    }
+
+    // A row without columns would read as missing
+    fn check_new_row(colvals: &HashMap<String, String>) -> Result<(), String> {
+        check_columns(colvals)?;
+        if colvals.is_empty() {
+            return Err(String::from("Invalid row: it has no columns"));
+        }
+        Ok(())
+    }
+
+    pub fn insert_row(
+        db: &mut DBState,
+        table: &str,
+        colvals: &HashMap<String, String>,
+    ) -> Result<u64, String> {
+        check_new_row(colvals)?;
+        autocommit(db, |db, t| Ok(write_new_row(db, t, table, colvals)))
+    }
+
+    // Only existing rows can be updated. Columns written for an id the
+    // table hasn't handed out yet would show up in the row that gets it.
+    fn write_existing_row(
+        db: &DBState,
+        t: &mut Transaction,
+        table: &str,
+        id: u64,
+        colvals: &HashMap<String, String>,
+    ) -> Result<String, String> {
+        check_columns(colvals)?;
+        if read_row(db, t, table, id).is_empty() {
+            return Err(String::from("Not found!"));
+        }
+        write_row(db, t, table, id, colvals);
+        Ok("Row successfully updated".to_string())
+    }
+
+    pub fn update_row(
+        db: &mut DBState,
+        table: &str,
+        id: u64,
+        colvals: &HashMap<String, String>,
+    ) -> Result<String, String> {
+        autocommit(db, |db, t| write_existing_row(db, t, table, id, colvals))
+    }
+
+    pub fn get_row(db: &DBState, table: &str, id: u64) -> HashMap<String, String> {
+        with_snapshot(db, |db, t| read_row(db, t, table, id))
+    }
+
+    // A single version of a key or row column
+    #[derive(Debug, PartialEq)]
+    pub struct Version {
+        pub tx_id: u64,
+        // None if this version deleted the key or is a delta
+        pub value: Option<String>,
+        // What an `incr` added to the previous version
+        pub delta: Option<i64>,
+        // When the value expires, for values set with a TTL
+        pub expires_at: Option<u64>,
+        // None if the writer is unknown, e.g. because it crashed before
+        // recording a state
+        pub state: Option<TxState>,
+    }
+
+    // Every version of a key still on disk, newest first, written by
+    // transactions from `from` to `to` inclusive. Vacuumed versions are gone.
+    pub fn history(
+        db: &DBState,
+        key: String,
+        from: Option<u64>,
+        to: Option<u64>,
+        limit: Option<usize>,
+    ) -> Result<Vec<Version>, String> {
+        read_history(db, &kv_prefix(&key), from, to, limit)
+    }
+
+    pub fn row_history(
+        db: &DBState,
+        table: &str,
+        id: u64,
+        col: &str,
+        from: Option<u64>,
+        to: Option<u64>,
+        limit: Option<usize>,
+    ) -> Result<Vec<Version>, String> {
+        read_history(db, &row_col_prefix(table, id, col), from, to, limit)
+    }
+
+    fn read_history(
+        db: &DBState,
+        prefix: &[u8],
+        from: Option<u64>,
+        to: Option<u64>,
+        limit: Option<usize>,
+    ) -> Result<Vec<Version>, String> {
+        let start = match to {
+            Some(to) => encode_key(prefix, to),
+            None => prefix_end(prefix),
+        };
+        db.db
+            .iterator(IteratorMode::From(&start, Direction::Reverse))
+            .take_while(|(k, _)| k.starts_with(prefix) && key_tx_id(k) >= from.unwrap_or(0))
+            .take(limit.unwrap_or(usize::MAX))
+            .map(|(k, value)| {
+                let tx_id = key_tx_id(&k);
+                Ok(Version {
+                    tx_id,
+                    value: decode_value(&value)
+                        .map(|v| to_utf8(v.to_vec()))
+                        .transpose()?,
+                    delta: decode_delta(&value),
+                    expires_at: value_expiry(&value),
+                    state: tx_state(db, tx_id),
+                })
+            })
+            .collect()
+    }
+
+    pub fn get_row_as_of(
+        db: &DBState,
+        table: &str,
+        id: u64,
+        tx_id: u64,
+    ) -> Result<HashMap<String, String>, String> {
+        with_snapshot_as_of(db, tx_id, |db, t| read_row(db, t, table, id))
+    }
+
+    pub fn tx_insert_row(
+        db: &mut DBState,
+        tx_id: u64,
+        table: &str,
+        colvals: &HashMap<String, String>,
+    ) -> Result<u64, String> {
+        check_new_row(colvals)?;
+        with_open_tx(db, tx_id, |db, t| Ok(write_new_row(db, t, table, colvals)))
+    }
+
+    pub fn tx_update_row(
+        db: &mut DBState,
+        tx_id: u64,
+        table: &str,
+        id: u64,
+        colvals: &HashMap<String, String>,
+    ) -> Result<String, String> {
+        with_open_tx(db, tx_id, |db, t| {
+            write_existing_row(db, t, table, id, colvals)
+        })
+    }
+
+    pub fn tx_get_row(
+        db: &mut DBState,
+        tx_id: u64,
+        table: &str,
+        id: u64,
+    ) -> Result<HashMap<String, String>, String> {
+        with_open_tx(db, tx_id, |db, t| Ok(read_row(db, t, table, id)))
+    }
+
+    // The newest row with the value, if there are several
+    pub fn get_by_col(
+        db: &DBState,
+        table: &str,
+        col: String,
+        value: String,
+    ) -> Option<HashMap<String, String>> {
+        find_rows(db, table, col, value).pop().map(|(_, row)| row)
+    }
+
+    pub fn tx_get_by_col(
+        db: &mut DBState,
+        tx_id: u64,
+        table: &str,
+        col: String,
+        value: String,
+    ) -> Result<Option<HashMap<String, String>>, String> {
+        tx_find_rows(db, tx_id, table, col, value).map(|mut rows| rows.pop().map(|(_, row)| row))
+    }
+
+    // A row's id and its columns
+    pub type FoundRow = (u64, HashMap<String, String>);
+
+    // Every row with the value in a column, in order of id
+    pub fn find_rows(db: &DBState, table: &str, col: String, value: String) -> Vec<FoundRow> {
+        with_snapshot(db, |db, t| read_by_col(db, t, table, &col, &value))
+    }
+
+    pub fn tx_find_rows(
+        db: &mut DBState,
+        tx_id: u64,
+        table: &str,
+        col: String,
+        value: String,
+    ) -> Result<Vec<FoundRow>, String> {
+        with_open_tx(db, tx_id, |db, t| {
+            Ok(read_by_col(db, t, table, &col, &value))
+        })
+    }
+
+    fn read_by_col(
+        db: &DBState,
+        t: &mut Transaction,
+        table: &str,
+        col: &str,
+        value: &str,
+    ) -> Vec<FoundRow> {
+        // find the index entries for (table, col, value) and
+        // look up the rows they point at in the same transaction
+        let start = index_prefix(table, col, value);
+        let end = prefix_end(&start);
+        let db_iter = db
+            .db
+            .iterator(IteratorMode::From(&start, Direction::Forward));
+        let (entries, _) = read_page(db, t, db_iter, &start, &end, false, None);
+        entries
+            .into_iter()
+            .map(|(_, id)| {
+                let id = bytes_to_u64(&id);
+                (id, read_row(db, t, table, id))
+            })
+            .collect()
+    }
+
+    // Versions written by aborted transactions can never be read, so they
+    // are dropped whenever RocksDB compacts the files holding them. The filter
+    // only ever sees one key at a time, so versions shadowed by a newer one
+    // are left to `vacuum`.
+    fn vacuum_filter(
+        aborted_txs: Arc<RwLock<HashSet<u64>>>,
+    ) -> impl FnMut(u32, &[u8], &[u8]) -> Decision + Send + 'static {
+        move |_level, key, _value| {
+            if !is_meta(key) && aborted_txs.read().unwrap().contains(&key_tx_id(key)) {
+                Decision::Remove
+            } else {
+                Decision::Keep
+            }
+        }
+    }
+
+    // The oldest tx id whose writes a running transaction might still not
+    // see. Every transaction sees all committed versions below its own id,
+    // except the ones from transactions it considers in progress.
+    pub fn oldest_snapshot(db: &DBState) -> u64 {
+        db.open_txs
+            .values()
+            .flat_map(|t| t.tx.in_progress.iter().chain(Some(&t.tx.id)))
+            .chain(db.txs.keys())
+            .cloned()
+            .min()
+            .unwrap_or(db.next_tx_id)
+    }
+
+    // Deletes every version that no transaction can read anymore: those from
+    // aborted transactions, and committed ones shadowed by a newer committed
+    // version below the oldest snapshot. If that newer version is a tombstone
+    // it goes too. Returns the number of versions deleted.
+    pub fn vacuum(db: &mut DBState) -> usize {
+        // Folding deltas overwrites versions in place, which replaying their
+        // writes from the WAL after a crash would undo, so start a segment
+        // after everything below the horizon
+        wal_checkpoint(db).expect("Failed to checkpoint the WAL.");
+        let horizon = oldest_snapshot(db);
+        // Recorded up front so that a crash part way through never lets a
+        // historical read see half vacuumed data
+        db.db
+            .put(
+                VACUUMED_BELOW_KEY.as_bytes(),
+                horizon.to_string().as_bytes(),
+            )
+            .expect("Failed to write vacuum horizon.");
+        db.vacuumed_below = horizon;
+        let mut removed = 0;
+        let mut prefix = Vec::new();
+        // Key, tx id and value, oldest first
+        let mut versions: Vec<(Vec<u8>, u64, Vec<u8>)> = Vec::new();
+        for (key, value) in db.db.iterator(IteratorMode::Start) {
+            if is_meta(&key) {
+                continue;
+            }
+            if key[..key.len() - TX_ID_LEN] != prefix[..] {
+                removed += vacuum_versions(db, &versions, horizon);
+                versions.clear();
+                prefix = key[..key.len() - TX_ID_LEN].to_vec();
+            }
+            versions.push((key.to_vec(), key_tx_id(&key), value.to_vec()));
+        }
+        removed += vacuum_versions(db, &versions, horizon);
+        forget_aborted(db, horizon);
+        removed
+    }
+
+    // Overwrites the delta at the end of `versions` with the full value the
+    // committed versions up to it add up to. Deltas on top of a value that
+    // has yet to expire are left alone, since they stop counting once it
+    // does. Returns whether the delta was folded.
+    fn fold_deltas(db: &DBState, versions: &[(Vec<u8>, u64, Vec<u8>)]) -> bool {
+        let committed = versions
+            .iter()
+            .rev()
+            .filter(|(_, id, _)| is_committed(db, *id));
+        let full = committed
+            .clone()
+            .find(|(_, _, value)| value[0] != DELTA_TAG);
+        let expires_at = full.and_then(|(_, _, value)| value_expiry(value));
+        if matches!(expires_at, Some(expires_at) if expires_at > now_millis()) {
+            return false;
+        }
+        let value = resolve_versions(committed.map(|(_, id, value)| (*id, value.clone())))
+            .and_then(|(_, value)| value);
+        let (key, _, _) = versions.last().unwrap();
+        db.db
+            .put(key, encode_value(value.as_deref()))
+            .expect("Failed to vacuum version.");
+        true
+    }
+
+    // Once vacuum has deleted every version of an aborted tx there is nothing
+    // left for its state to hide. Serializable transactions may still ask
+    // about the ones they ran concurrently with, which are all from the
+    // horizon on.
+    fn forget_aborted(db: &DBState, horizon: u64) {
+        let mut aborted_txs = db.aborted_txs.write().unwrap();
+        let forgotten: Vec<u64> = aborted_txs
+            .iter()
+            .filter(|id| **id < horizon)
+            .cloned()
+            .collect();
+        for id in forgotten {
+            db.db
+                .delete(tx_state_key(id))
+                .expect("Failed to delete tx state.");
+            aborted_txs.remove(&id);
+        }
+    }
+
+    fn vacuum_versions(db: &DBState, versions: &[(Vec<u8>, u64, Vec<u8>)], horizon: u64) -> usize {
+        // The version every transaction reads unless it has a newer one.
+        // Deltas are added to older versions, so they can only shadow them
+        // once they have been folded into a full value.
+        let mut base = versions
+            .iter()
+            .rposition(|(_, id, _)| *id < horizon && is_committed(db, *id));
+        if let Some(i) = base {
+            if versions[i].2[0] == DELTA_TAG && !fold_deltas(db, &versions[..=i]) {
+                base = versions[..i].iter().rposition(|(_, id, value)| {
+                    *id < horizon && value[0] != DELTA_TAG && is_committed(db, *id)
+                });
+            }
+        }
+        let mut removed = 0;
+        for (i, (key, id, value)) in versions.iter().enumerate() {
+            let shadowed = match base {
+                Some(base) => i < base || (i == base && value[0] == TOMBSTONE_TAG),
+                None => false,
+            };
+            if shadowed || tx_state(db, *id) == Some(TxState::Aborted) {
+                db.db.delete(key).expect("Failed to vacuum version.");
+                removed += 1;
+            }
+        }
+        removed
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use rocksdb::Options;
+        use std::fs::{self, OpenOptions};
+
+        // Every test has its own directories, so the tests can run in
+        // parallel
+        fn data_dir(name: &str) -> String {
+            format!("{}_test_data", name)
+        }
+
+        fn wal_dir(name: &str) -> String {
+            format!("{}_test_wal", name)
+        }
+
+        fn setup(name: &str) -> DBState {
+            initialize_db(data_dir(name), wal_dir(name))
+        }
+
+        fn setup_crashed() -> DBState {
+            {
+                // Write the keyvals to RocksDB but stop before the commit
+                // record. This emulates a "crash" part way through a multi_set.
+                let db_path = "crashed_test_data".to_string();
+                let mut setup_state = initialize_db(db_path, "crashed_test_wal".to_string());
+                // keyvals that are part of the failed (i.e. crashed) write
+                let mut crashed_keyvals = HashMap::new();
+                crashed_keyvals.insert("hello".to_string(), "world".to_string());
+                crashed_keyvals.insert("foo".to_string(), "bar".to_string());
+                let tx = wal_new_tx(&mut setup_state);
+                for (key, value) in crashed_keyvals.iter() {
+                    let prefix = kv_prefix(key);
+                    let value = encode_value(Some(value.as_bytes()));
+                    wal_append_write(&setup_state, &tx, &prefix, &value).unwrap();
+                    persist_entry(&setup_state, &prefix, &value, &tx).unwrap();
+                }
+            }
+
+            let db_path = "crashed_test_data".to_string();
+            let mut state = initialize_db(db_path, "crashed_test_wal".to_string());
+            // The following multiset should work fine so these keyvals are
+            // considered 'good'
+            let mut good_keyvals = HashMap::new();
+            good_keyvals.insert("good_hello".to_string(), "good_world".to_string());
+            good_keyvals.insert("good_foo".to_string(), "good_bar".to_string());
+            multi_set(&mut state, good_keyvals).expect("Cannot set multiple keys.");
+            state
+        }
+
+        fn cleanup(name: &str) {
+            super::DB::destroy(&Options::default(), data_dir(name))
+                .expect("Cannot destroy main test db.");
+            fs::remove_dir_all(wal_dir(name)).expect("Can't clean up main test wal.");
+        }
+        fn cleanup_crashed() {
+            super::DB::destroy(&Options::default(), "crashed_test_data".to_string())
+                .expect("Cannot destroy crash test db.");
+            fs::remove_dir_all("crashed_test_wal").expect("Can't clean up crashed wal.");
+        }
+
+        #[test]
+        fn test_set() {
+            {
+                let mut db = setup("set");
+                let res = set(&mut db, String::from("hello"), String::from("world")).unwrap();
+                assert_eq!(res, "Set key: hello to value: world");
+            }
+            cleanup("set");
+        }
+
+        #[test]
+        fn test_multi_set() {
+            {
+                let mut db = setup("multi_set");
+                let mut keyvals = HashMap::new();
+                keyvals.insert("hello".to_string(), "world".to_string());
+                keyvals.insert("foo".to_string(), "bar".to_string());
+                multi_set(&mut db, keyvals).unwrap();
+                assert_eq!(get(&db, "hello".to_string()).unwrap(), "world".to_string());
+                assert_eq!(get(&db, "foo".to_string()).unwrap(), "bar".to_string());
+            }
+            cleanup("multi_set");
+        }
+
+        #[test]
         fn test_rows() {
             {
-                let mut db = setup();
-                let mut record0 = HashMap::new();
-                record0.insert("name".to_string(), "charles darwin".to_string());
-                record0.insert("job".to_string(), "biologist".to_string());
-                let mut record1 = HashMap::new();
-                record1.insert("name".to_string(), "rosalind franklin".to_string());
-                record1.insert("job".to_string(), "chemist".to_string());
-                let mut record2 = HashMap::new();
-                record2.insert("name".to_string(), "carmen sandiego".to_string());
-                record2.insert("job".to_string(), "incognito person".to_string());
-                insert_row(&mut db, "people", &record0).expect("Failed to insert row.");
-                insert_row(&mut db, "people", &record1).expect("Failed to insert row.");
-                insert_row(&mut db, "people", &record2).expect("Failed to insert row.");
-                let rec = get_row(&mut db, "people", 1);
+                let mut db = setup("rows");
+                let mut record0 = HashMap::new();
+                record0.insert("name".to_string(), "charles darwin".to_string());
+                record0.insert("job".to_string(), "biologist".to_string());
+                let mut record1 = HashMap::new();
+                record1.insert("name".to_string(), "rosalind franklin".to_string());
+                record1.insert("job".to_string(), "chemist".to_string());
+                let mut record2 = HashMap::new();
+                record2.insert("name".to_string(), "carmen sandiego".to_string());
+                record2.insert("job".to_string(), "incognito person".to_string());
+                insert_row(&mut db, "people", &record0).expect("Failed to insert row.");
+                insert_row(&mut db, "people", &record1).expect("Failed to insert row.");
+                insert_row(&mut db, "people", &record2).expect("Failed to insert row.");
+                let rec = get_row(&db, "people", 1);
+                assert_eq!(
+                    rec.get("name").expect("Failed to find name in record"),
+                    "rosalind franklin"
+                );
+                assert_eq!(
+                    rec.get("job").expect("Failed to find job in record"),
+                    "chemist"
+                );
+            }
+            cleanup("rows");
+        }
+
+        #[test]
+        fn test_update_row() {
+            {
+                let mut db = setup("update_row");
+                let mut record = HashMap::new();
+                record.insert("foo".to_string(), "bar".to_string());
+                let id = insert_row(&mut db, "testtable", &record).unwrap();
+                let rec = get_row(&db, "testtable", id);
+                assert_eq!(rec.get("foo").unwrap(), "bar");
+
+                record.insert("foo".to_string(), "baz".to_string());
+                update_row(&mut db, "testtable", id, &record).unwrap();
+                let rec = get_row(&db, "testtable", id);
+                assert_eq!(rec.get("foo").unwrap(), "baz");
+
+                // Missing rows aren't created, and ids aren't columns
+                let missing = update_row(&mut db, "testtable", id + 1, &record);
+                assert_eq!(missing, Err("Not found!".to_string()));
+                let next = insert_row(&mut db, "testtable", &record).unwrap();
+                assert_eq!(next, id + 1);
+                record.insert("id".to_string(), "7".to_string());
+                assert!(update_row(&mut db, "testtable", id, &record).is_err());
+                assert!(insert_row(&mut db, "testtable", &record).is_err());
+                assert!(insert_row(&mut db, "testtable", &HashMap::new()).is_err());
+            }
+            cleanup("update_row");
+        }
+
+        #[test]
+        fn test_get_by_col() {
+            {
+                let mut db = setup("get_by_col");
+                let mut record0 = HashMap::new();
+                record0.insert("foo".to_string(), "bar".to_string());
+                record0.insert("other_key".to_string(), "other_value".to_string());
+                insert_row(&mut db, "testtable", &record0).unwrap();
+                let mut record1 = HashMap::new();
+                record1.insert("foo".to_string(), "not-looked-for".to_string());
+                record1.insert("other_key".to_string(), "not-loooked-for".to_string());
+                let id1 = insert_row(&mut db, "testtable", &record1).unwrap();
+                let rec =
+                    get_by_col(&db, "testtable", "foo".to_string(), "bar".to_string()).unwrap();
+                assert_eq!(rec.get("other_key").unwrap(), "other_value");
+                let (id, rec) = find_rows(&db, "testtable", "id".to_string(), id1.to_string())
+                    .pop()
+                    .unwrap();
+                assert_eq!(id, id1);
+                assert_eq!(rec.get("foo").unwrap(), "not-looked-for");
+
+                // Rows sharing a value are all found, and updates move them
+                let id2 = insert_row(&mut db, "testtable", &record0).unwrap();
+                let found = find_rows(&db, "testtable", "foo".to_string(), "bar".to_string());
+                assert_eq!(found.len(), 2);
+                assert_eq!(found[1].0, id2);
+                let mut update = HashMap::new();
+                update.insert("foo".to_string(), "moved".to_string());
+                update_row(&mut db, "testtable", id2, &update).unwrap();
+                let found = find_rows(&db, "testtable", "foo".to_string(), "bar".to_string());
+                assert_eq!(found.len(), 1);
+                assert_ne!(found[0].0, id2);
+                let rec =
+                    get_by_col(&db, "testtable", "foo".to_string(), "moved".to_string()).unwrap();
+                assert_eq!(rec.get("other_key").unwrap(), "other_value");
+            }
+            cleanup("get_by_col");
+        }
+
+        #[test]
+        fn test_get() {
+            {
+                let mut db = setup("get");
+                set(&mut db, String::from("hello"), String::from("world")).unwrap();
+                let res = get(&db, String::from("hello")).unwrap();
+                assert_eq!(res, String::from("world"));
+            }
+            cleanup("get");
+        }
+
+        #[test]
+        fn test_multi_get() {
+            {
+                let mut db = setup("multi_get");
+                set(&mut db, String::from("a"), String::from("1")).unwrap();
+                set(&mut db, String::from("b"), String::from("2")).unwrap();
+                delete(&mut db, String::from("b")).unwrap();
+                let keys = vec!["a".to_string(), "b".to_string(), "c".to_string()];
+                assert_eq!(
+                    multi_get(&db, keys).unwrap(),
+                    vec![
+                        ("a".to_string(), Some("1".to_string())),
+                        ("b".to_string(), None),
+                        ("c".to_string(), None)
+                    ]
+                );
+            }
+            cleanup("multi_get");
+        }
+
+        #[test]
+        fn test_scan() {
+            {
+                let mut db = setup("scan");
+                let mut keyvals = HashMap::new();
+                set(&mut db, "3".to_string(), "should-be-ignored".to_string()).unwrap();
+                keyvals.insert("1".to_string(), "first".to_string());
+                keyvals.insert("2".to_string(), "second".to_string());
+                keyvals.insert("3".to_string(), "third".to_string());
+                keyvals.insert("4".to_string(), "fourth".to_string());
+                keyvals.insert("5".to_string(), "fifth".to_string());
+                multi_set(&mut db, keyvals).unwrap();
+                let res = scan(&db, "2".to_string(), "3".to_string()).unwrap();
+                assert_eq!(res, vec!["second".to_string(), "third".to_string()]);
+            }
+            cleanup("scan");
+        }
+
+        #[test]
+        fn test_conditional_writes() {
+            {
+                let mut db = setup("conditional_writes");
+                let key = || String::from("lock");
+                set_if_absent(&mut db, key(), String::from("a")).unwrap();
+                let err = set_if_absent(&mut db, key(), String::from("b")).unwrap_err();
+                assert!(is_conflict(&err));
+
+                let err = compare_and_set(&mut db, key(), String::from("b"), String::from("c"))
+                    .unwrap_err();
+                assert!(is_conflict(&err));
+                compare_and_set(&mut db, key(), String::from("a"), String::from("c")).unwrap();
+                assert_eq!(get(&db, key()).unwrap(), "c");
+
+                let version = db.next_tx_id - 1;
+                set_if_version(&mut db, key(), version, String::from("d")).unwrap();
+                let err = set_if_version(&mut db, key(), version, String::from("e")).unwrap_err();
+                assert!(is_conflict(&err));
+                assert_eq!(get(&db, key()).unwrap(), "d");
+                // Failed conditions don't leave anything behind
+                assert_eq!(history(&db, key(), None, None, None).unwrap().len(), 3);
+            }
+            cleanup("conditional_writes");
+        }
+
+        #[test]
+        fn test_incr() {
+            {
+                let mut db = setup("incr");
+                let key = || String::from("hits");
+                assert_eq!(incr(&mut db, key(), 5).unwrap(), "5");
+                assert_eq!(decr(&mut db, key(), 2).unwrap(), "3");
+                // Increments are added to the newest value when they commit
+                let newest = history(&db, key(), None, None, Some(1)).unwrap();
+                assert_eq!(newest[0].value, Some(String::from("3")));
+                assert_eq!(versions(&db, "hits"), 2);
+
+                // Increments go on top of sets and deletes
+                set(&mut db, key(), String::from("40")).unwrap();
+                assert_eq!(incr(&mut db, key(), 2).unwrap(), "42");
+                assert_eq!(scan(&db, key(), key()).unwrap(), vec!["42".to_string()]);
+                delete(&mut db, key()).unwrap();
+                assert_eq!(incr(&mut db, key(), 1).unwrap(), "1");
+
+                // Concurrent increments both apply. Neither is folded: a full
+                // value from the second would hide the first's delta below
+                // it, and the first commits below the second.
+                let first = begin(&mut db);
+                let second = begin(&mut db);
+                tx_incr(&mut db, first, key(), 10).unwrap();
+                tx_incr(&mut db, second, key(), 100).unwrap();
+                tx_incr(&mut db, second, key(), 100).unwrap();
+                assert_eq!(tx_get(&mut db, second, key()).unwrap(), "201");
+                commit(&mut db, second).unwrap();
+                commit(&mut db, first).unwrap();
+                assert_eq!(get(&db, key()).unwrap(), "211");
+                assert_eq!(
+                    history(&db, key(), None, None, Some(1)).unwrap()[0].delta,
+                    Some(200)
+                );
+
+                // Vacuum folds the deltas into a single value
+                vacuum(&mut db);
+                assert_eq!(versions(&db, "hits"), 1);
+                assert_eq!(get(&db, key()).unwrap(), "211");
+                assert_eq!(incr(&mut db, key(), 1).unwrap(), "212");
+            }
+            cleanup("incr");
+        }
+
+        #[test]
+        fn test_ttl() {
+            {
+                let mut db = setup("ttl");
+                set_with_ttl(&mut db, String::from("session"), String::from("a"), Some(0)).unwrap();
+                set_with_ttl(&mut db, String::from("token"), String::from("b"), Some(60)).unwrap();
+                assert_eq!(
+                    get(&db, String::from("session")),
+                    Err("Not found!".to_string())
+                );
+                assert_eq!(mem_get(&db, String::from("token")).unwrap(), "b");
+                assert_eq!(db.cache.lock().unwrap().get("token"), None);
+                assert_eq!(
+                    scan(&db, "a".to_string(), "z".to_string()).unwrap(),
+                    vec!["b".to_string()]
+                );
+
+                let expired = expired_keys(&db);
+                assert_eq!(expired.len(), 1);
+                assert!(expired[0].ends_with(&kv_prefix("session")));
+                assert_eq!(delete_expired(&mut db, expired.clone()).unwrap(), 1);
+                assert_eq!(delete_expired(&mut db, expired).unwrap(), 0);
+                assert!(expired_keys(&db).is_empty());
+                let versions = history(&db, String::from("session"), None, None, None).unwrap();
+                assert_eq!(versions.len(), 2);
+                assert_eq!(versions[0].expires_at, None);
+                assert!(versions[1].expires_at.is_some());
+
+                // Keys written again after they were found are left alone
+                set_with_ttl(&mut db, String::from("session"), String::from("d"), Some(0)).unwrap();
+                let expired = expired_keys(&db);
+                set(&mut db, String::from("session"), String::from("e")).unwrap();
+                assert_eq!(delete_expired(&mut db, expired).unwrap(), 0);
+                assert_eq!(get(&db, String::from("session")).unwrap(), "e");
+
+                // Increments of an expiring value expire along with it
+                let prefix = kv_prefix("hits");
+                autocommit(&mut db, |_, t| {
+                    t.write(prefix.clone(), Some(b"10"));
+                    t.expires.insert(prefix.clone(), now_millis() + 50);
+                    Ok(())
+                })
+                .unwrap();
+                assert_eq!(incr(&mut db, String::from("hits"), 5).unwrap(), "15");
+                std::thread::sleep(std::time::Duration::from_millis(60));
+                assert_eq!(
+                    get(&db, String::from("hits")),
+                    Err("Not found!".to_string())
+                );
+                let expired = expired_keys(&db);
+                assert_eq!(delete_expired(&mut db, expired).unwrap(), 1);
+                assert_eq!(incr(&mut db, String::from("hits"), 1).unwrap(), "1");
+
+                // A TTL too large to add to the current time never expires
+                set_with_ttl(
+                    &mut db,
+                    String::from("forever"),
+                    String::from("f"),
+                    Some(u64::MAX),
+                )
+                .unwrap();
+                assert_eq!(get(&db, String::from("forever")).unwrap(), "f");
+
+                // Setting the key again drops the TTL
+                set(&mut db, String::from("token"), String::from("c")).unwrap();
+                assert_eq!(mem_get(&db, String::from("token")).unwrap(), "c");
+                assert_eq!(db.cache.lock().unwrap().get("token").unwrap(), "c");
+            }
+            cleanup("ttl");
+        }
+
+        #[test]
+        fn test_delete() {
+            {
+                let mut db = setup("delete");
+                set(&mut db, String::from("hello"), String::from("world")).unwrap();
+                set(&mut db, String::from("foo"), String::from("bar")).unwrap();
+                let res = delete(&mut db, String::from("hello")).unwrap();
+                assert_eq!(res, "Deleted key: hello");
                 assert_eq!(
-                    rec.get("name").expect("Failed to find name in record"),
-                    "rosalind franklin"
+                    get(&db, String::from("hello")),
+                    Err("Not found!".to_string())
                 );
                 assert_eq!(
-                    rec.get("job").expect("Failed to find job in record"),
-                    "chemist"
+                    mem_get(&db, String::from("hello")),
+                    Err("Not found!".to_string())
                 );
+                let res = scan(&db, "foo".to_string(), "hello".to_string()).unwrap();
+                assert_eq!(res, vec!["bar".to_string()]);
+
+                // A later write brings the key back
+                set(&mut db, String::from("hello"), String::from("again")).unwrap();
+                let res = get(&db, String::from("hello")).unwrap();
+                assert_eq!(res, String::from("again"));
             }
-            cleanup();
+            cleanup("delete");
         }
 
         #[test]
-        fn test_update_row() {
-            let mut db = setup();
-            let mut record = HashMap::new();
-            record.insert("foo".to_string(), "bar".to_string());
-            let id = insert_row(&mut db, "testtable", &record).unwrap();
-            let rec = get_row(&mut db, "testtable", id);
-            assert_eq!(rec.get("foo").unwrap(), "bar");
+        fn test_bytes() {
+            {
+                let mut db = setup("bytes");
+                set_bytes(&mut db, b"k\xff", b"\x00\xff", None).unwrap();
+                set_bytes(&mut db, b"v", b"\xfe", None).unwrap();
+                assert_eq!(get_bytes(&db, b"k\xff").unwrap(), b"\x00\xff".to_vec());
+                // Rather than coming back mangled
+                let err = get(&db, String::from("v")).unwrap_err();
+                assert!(err.starts_with("Not valid UTF-8"));
+                assert!(scan_range(&db, &ScanOptions::default()).is_err());
+
+                delete_bytes(&mut db, b"k\xff").unwrap();
+                delete_bytes(&mut db, b"v").unwrap();
+                assert_eq!(get_bytes(&db, b"v"), Err("Not found!".to_string()));
+                assert!(scan_range(&db, &ScanOptions::default())
+                    .unwrap()
+                    .items
+                    .is_empty());
+            }
+            cleanup("bytes");
+        }
+
+        #[test]
+        fn test_legacy_format() {
+            let open = || {
+                std::panic::catch_unwind(|| {
+                    initialize_db(
+                        "legacy_test_data".to_string(),
+                        "legacy_test_wal".to_string(),
+                    )
+                })
+                .is_ok()
+            };
+            {
+                let mut opts = Options::default();
+                opts.create_if_missing(true);
+                let db = DB::open(&opts, "legacy_test_data").unwrap();
+                db.put(b"**autoincrement**users", b"3").unwrap();
+            }
+            assert!(open());
+
+            fs::write("legacy_test_wal.db", b"1:true\n").unwrap();
+            assert!(!open());
+            fs::remove_file("legacy_test_wal.db").unwrap();
+
+            {
+                let db = DB::open_default("legacy_test_data").unwrap();
+                db.put(b"hello:1", b"world").unwrap();
+            }
+            assert!(!open());
+
+            fs::remove_dir_all("legacy_test_data").unwrap();
+            fs::remove_dir_all("legacy_test_wal").unwrap();
+        }
+
+        #[test]
+        fn test_key_encoding() {
+            let keys: Vec<&[u8]> = vec![b"", b"a", b"a\x00", b"a\x00b", b"a:b", b"ab", b"b"];
+            let encoded: Vec<Vec<u8>> = keys.iter().map(|k| encode_prefix(KV_TAG, &[k])).collect();
+            for pair in encoded.windows(2) {
+                assert!(pair[0] < pair[1]);
+            }
+
+            let prefix = encode_prefix(ROW_TAG, &[b"t\x00:", &[0xff, 0x00], b""]);
+            let key = encode_key(&prefix, 42);
+            let decoded = decode_key(&key).unwrap();
+            assert_eq!(decode_prefix(&prefix).unwrap().0, ROW_TAG);
+            assert_eq!(
+                decoded.components,
+                vec![b"t\x00:".to_vec(), vec![0xff, 0x00], b"".to_vec()]
+            );
+            assert_eq!(decoded.tx_id, 42);
+            assert!(encode_key(&prefix, 9) < key);
+        }
+
+        #[test]
+        fn test_colon_keys() {
+            {
+                let mut db = setup("colon_keys");
+                set(&mut db, String::from("a"), String::from("plain")).unwrap();
+                set(&mut db, String::from("a:b"), String::from("c:d\ne")).unwrap();
+                assert_eq!(get(&db, String::from("a")).unwrap(), "plain");
+                assert_eq!(get(&db, String::from("a:b")).unwrap(), "c:d\ne");
+                let res = scan(&db, "a".to_string(), "a:b".to_string()).unwrap();
+                assert_eq!(res, vec!["plain".to_string(), "c:d\ne".to_string()]);
+            }
+            cleanup("colon_keys");
+        }
+
+        #[test]
+        fn test_tx_ids_survive_restart() {
+            {
+                let last_id = {
+                    let mut db = setup("tx_ids_survive_restart");
+                    let first = wal_new_tx(&mut db);
+                    let second = wal_new_tx(&mut db);
+                    assert!(first.id < second.id);
+                    second.id
+                };
+                let mut db = setup("tx_ids_survive_restart");
+                let tx = wal_new_tx(&mut db);
+                assert!(tx.id > last_id);
+            }
+            cleanup("tx_ids_survive_restart");
+        }
+
+        #[test]
+        fn test_tx_states_recovered() {
+            {
+                let (pending_id, committed_id) = {
+                    let mut db = setup("tx_states_recovered");
+                    let pending = wal_new_tx(&mut db);
+                    let committed = wal_new_tx(&mut db);
+                    wal_commit(&mut db, &committed).unwrap();
+                    assert_eq!(tx_state(&db, pending.id), Some(TxState::Pending));
+                    (pending.id, committed.id)
+                };
+                let db = setup("tx_states_recovered");
+                assert_eq!(tx_state(&db, pending_id), Some(TxState::Aborted));
+                assert!(tx_state(&db, committed_id).unwrap().is_committed());
+                assert!(db.txs.is_empty());
+            }
+            cleanup("tx_states_recovered");
+        }
+
+        #[test]
+        fn test_torn_wal_record() {
+            {
+                {
+                    let mut db = setup("torn_wal_record");
+                    set(&mut db, String::from("a\nb"), String::from("c:d")).unwrap();
+                    let tx = wal_new_tx(&mut db);
+                    wal_append_write(&db, &tx, &kv_prefix("a\nb"), &encode_value(Some(b"torn")))
+                        .unwrap();
+                }
+                // Chop the last record in half, as if we crashed mid write
+                let path = wal_segment_path(Path::new(&wal_dir("torn_wal_record")), 1);
+                let len = fs::metadata(&path).unwrap().len();
+                let wal = OpenOptions::new().write(true).open(&path).unwrap();
+                wal.set_len(len - 5).unwrap();
+
+                {
+                    let db = setup("torn_wal_record");
+                    assert!(db.wal_discarded_bytes > 0);
+                    assert_eq!(get(&db, String::from("a\nb")).unwrap(), "c:d");
+                }
+                let db = setup("torn_wal_record");
+                assert_eq!(db.wal_discarded_bytes, 0);
+            }
+            cleanup("torn_wal_record");
+        }
+
+        #[test]
+        fn test_corrupt_wal_segment() {
+            {
+                let dir = wal_dir("corrupt_wal_segment");
+                let path = |segment| wal_segment_path(Path::new(&dir), segment);
+                let segments = {
+                    let mut db = setup("corrupt_wal_segment");
+                    let mut segments = Vec::new();
+                    for key in ["a", "b"].iter() {
+                        set(&mut db, key.to_string(), key.to_string()).unwrap();
+                        segments.push(fs::read(path(db.wal.segment)).unwrap());
+                        wal_checkpoint(&mut db).unwrap();
+                    }
+                    set(&mut db, String::from("c"), String::from("c")).unwrap();
+                    segments
+                };
+                // Bring the older segments back as if we crashed before the
+                // checkpoint deleted them, with a bad byte in the middle one
+                let mut middle = segments[1].clone();
+                let len = middle.len();
+                middle[len - 3] ^= 0xff;
+                fs::write(path(1), &segments[0]).unwrap();
+                fs::write(path(2), &middle).unwrap();
+
+                {
+                    let db = setup("corrupt_wal_segment");
+                    assert!(db.wal_discarded_bytes > 0);
+                    assert_eq!(db.wal.segment, 2);
+                    assert_eq!(get(&db, String::from("c")).unwrap(), "c");
+                }
+                // The segments that weren't replayed are still on disk
+                assert_eq!(
+                    fs::read(path(2).with_extension("wal.discarded")).unwrap(),
+                    middle
+                );
+                assert!(path(3).with_extension("wal.discarded").exists());
+                assert!(!path(3).exists());
+                let db = setup("corrupt_wal_segment");
+                assert_eq!(db.wal_discarded_bytes, 0);
+            }
+            cleanup("corrupt_wal_segment");
+        }
+
+        #[test]
+        fn test_wal_segments() {
+            {
+                {
+                    let mut db = setup("wal_segments");
+                    db.wal.segment_size = 512;
+                    for i in 0..50 {
+                        set(&mut db, format!("key{}", i), i.to_string()).unwrap();
+                    }
+                    assert!(db.wal.checkpoint > 0);
+                    assert_eq!(
+                        wal_segments(Path::new(&wal_dir("wal_segments")))
+                            .unwrap()
+                            .len(),
+                        1
+                    );
+                }
+                let db = setup("wal_segments");
+                assert_eq!(get(&db, String::from("key0")).unwrap(), "0");
+                assert_eq!(get(&db, String::from("key49")).unwrap(), "49");
+            }
+            cleanup("wal_segments");
+        }
+
+        #[test]
+        fn test_tx_state_compaction() {
+            {
+                let (committed, aborted) = {
+                    let mut db = setup("tx_state_compaction");
+                    set(&mut db, String::from("hello"), String::from("world")).unwrap();
+                    let committed = db.next_tx_id - 1;
+                    // A rollback leaves no versions behind, so it needs no
+                    // record
+                    let rolled_back = begin(&mut db);
+                    tx_set(
+                        &mut db,
+                        rolled_back,
+                        String::from("hello"),
+                        String::from("no"),
+                    )
+                    .unwrap();
+                    rollback(&mut db, rolled_back).unwrap();
+                    assert_eq!(tx_state(&db, rolled_back), Some(TxState::Aborted));
+                    let aborted = wal_new_tx(&mut db);
+                    let value = encode_value(Some(b"no"));
+                    persist_entry(&db, &kv_prefix("hello"), &value, &aborted).unwrap();
+                    wal_abort(&mut db, &aborted, true).unwrap();
+                    wal_checkpoint(&mut db).unwrap();
+
+                    assert!(db.db.get(tx_state_key(committed)).unwrap().is_none());
+                    assert!(db.db.get(tx_state_key(rolled_back)).unwrap().is_none());
+                    assert!(db.db.get(tx_state_key(aborted.id)).unwrap().is_some());
+                    (committed, aborted.id)
+                };
+                let db = setup("tx_state_compaction");
+                assert!(tx_state(&db, committed).unwrap().is_committed());
+                assert_eq!(tx_state(&db, aborted), Some(TxState::Aborted));
+                assert_eq!(get(&db, String::from("hello")).unwrap(), "world");
+            }
+            cleanup("tx_state_compaction");
+        }
+
+        #[test]
+        fn test_snapshot_reads() {
+            {
+                let mut db = setup("snapshot_reads");
+                set(&mut db, String::from("hello"), String::from("world")).unwrap();
+                let next_tx_id = db.next_tx_id;
+                let tx = begin(&mut db);
+                tx_set(&mut db, tx, String::from("hello"), String::from("there")).unwrap();
+                assert_eq!(get(&db, String::from("hello")).unwrap(), "world");
+                commit(&mut db, tx).unwrap();
+                assert_eq!(get(&db, String::from("hello")).unwrap(), "there");
+                // Only the explicit transaction took a tx id
+                assert_eq!(db.next_tx_id, next_tx_id + 1);
+            }
+            cleanup("snapshot_reads");
+        }
+
+        fn values(page: ScanPage) -> Vec<String> {
+            page.items.into_iter().map(|(_, value)| value).collect()
+        }
+
+        fn versions(db: &DBState, key: &str) -> usize {
+            let prefix = kv_prefix(key);
+            db.db
+                .iterator(IteratorMode::From(&prefix, Direction::Forward))
+                .take_while(|(k, _)| k.starts_with(&prefix))
+                .count()
+        }
+
+        #[test]
+        fn test_vacuum() {
+            {
+                let mut db = setup("vacuum");
+                for v in &["1", "2", "3"] {
+                    set(&mut db, String::from("a"), v.to_string()).unwrap();
+                }
+                set(&mut db, String::from("b"), String::from("1")).unwrap();
+                delete(&mut db, String::from("b")).unwrap();
+                // A write that failed part way, leaving a version behind
+                let failed = wal_new_tx(&mut db);
+                persist_entry(&db, &kv_prefix("c"), &encode_value(Some(b"1")), &failed).unwrap();
+                wal_abort(&mut db, &failed, true).unwrap();
+
+                // An open transaction still needs the version it can see
+                let tx = begin(&mut db);
+                set(&mut db, String::from("a"), String::from("4")).unwrap();
+
+                assert!(vacuum(&mut db) >= 5);
+                assert_eq!(versions(&db, "a"), 2);
+                assert_eq!(versions(&db, "b"), 0);
+                assert_eq!(versions(&db, "c"), 0);
+                // Its versions are gone, so its state can go too
+                assert!(db.db.get(tx_state_key(failed.id)).unwrap().is_none());
+                assert!(db.aborted_txs.read().unwrap().is_empty());
+                assert_eq!(tx_get(&mut db, tx, String::from("a")).unwrap(), "3");
+                assert_eq!(get(&db, String::from("a")).unwrap(), "4");
+            }
+            cleanup("vacuum");
+        }
+
+        #[test]
+        fn test_vacuum_filter() {
+            {
+                let mut db = setup("vacuum_filter");
+                let failed = wal_new_tx(&mut db);
+                persist_entry(&db, &kv_prefix("c"), &encode_value(Some(b"1")), &failed).unwrap();
+                wal_abort(&mut db, &failed, true).unwrap();
+                db.db.compact_range(None::<&[u8]>, None::<&[u8]>);
+                assert_eq!(versions(&db, "c"), 0);
+            }
+            cleanup("vacuum_filter");
+        }
+
+        #[test]
+        fn test_cache() {
+            {
+                {
+                    let mut db = setup("cache");
+                    db.cache.lock().unwrap().capacity = 20;
+                    set(&mut db, String::from("hello"), String::from("world")).unwrap();
+                    set(&mut db, String::from("foo"), String::from("bar")).unwrap();
+                    // Using hello makes foo the least recently used
+                    assert_eq!(mem_get(&db, String::from("hello")).unwrap(), "world");
+                    set(&mut db, String::from("baz"), String::from("qux")).unwrap();
+                    let mut cache = db.cache.lock().unwrap();
+                    assert_eq!(cache.get("foo"), None);
+                    assert_eq!(cache.get("hello").unwrap(), "world");
+                }
+                // Misses fall back to RocksDB
+                let db = setup("cache");
+                assert!(db.cache.lock().unwrap().is_empty());
+                assert_eq!(mem_get(&db, String::from("foo")).unwrap(), "bar");
+                assert_eq!(db.cache.lock().unwrap().len(), 1);
+            }
+            cleanup("cache");
+        }
+
+        // Where `finish` is cut off by a crash
+        #[derive(Clone, Copy, Debug, PartialEq)]
+        enum CrashPoint {
+            // Every write is in the WAL but none reached RocksDB
+            Logged,
+            // Only the first write reached RocksDB
+            PartlyPersisted,
+            // Every write reached RocksDB but the commit record didn't
+            Persisted,
+            // The commit record is in the WAL but RocksDB lost the writes
+            Committed,
+        }
+
+        const CRASH_POINTS: [CrashPoint; 4] = [
+            CrashPoint::Logged,
+            CrashPoint::PartlyPersisted,
+            CrashPoint::Persisted,
+            CrashPoint::Committed,
+        ];
+
+        // Makes the writes of `f` in a transaction and follows the steps of
+        // `finish` up to the crash point, then restarts the db. Returns the
+        // restarted db, the id of the crashed tx and what `f` returned.
+        fn crash_during<T, F>(name: &str, point: CrashPoint, f: F) -> (DBState, u64, T)
+        where
+            F: FnOnce(&mut DBState, &mut Transaction) -> T,
+        {
+            let (tx_id, res) = {
+                let mut db = setup(name);
+                let mut t = Transaction::new(&mut db, Isolation::Snapshot);
+                let res = f(&mut db, &mut t);
+                for (prefix, value) in t.writes.iter() {
+                    wal_append_write(&db, &t.tx, prefix, &encode_value(value.as_deref())).unwrap();
+                }
+                let persisted = match point {
+                    CrashPoint::Logged | CrashPoint::Committed => 0,
+                    CrashPoint::PartlyPersisted => 1,
+                    CrashPoint::Persisted => t.writes.len(),
+                };
+                for (prefix, value) in t.writes.iter().take(persisted) {
+                    persist_entry(&db, prefix, &encode_value(value.as_deref()), &t.tx).unwrap();
+                }
+                if point == CrashPoint::Committed {
+                    wal_commit(&mut db, &t.tx).unwrap();
+                }
+                (t.tx.id, res)
+            };
+            (setup(name), tx_id, res)
+        }
+
+        // Number of versions in RocksDB written by the given tx
+        fn tx_versions(db: &DBState, tx_id: u64) -> usize {
+            db.db
+                .iterator(IteratorMode::Start)
+                .filter(|(k, _)| decode_key(k).map(|k| k.tx_id) == Some(tx_id))
+                .count()
+        }
+
+        #[test]
+        fn test_recover_set() {
+            for &point in CRASH_POINTS.iter() {
+                {
+                    let (db, tx_id, _) = crash_during("recover_set", point, |_, t| {
+                        t.write(kv_prefix("hello"), Some(b"world"));
+                    });
+                    if point == CrashPoint::Committed {
+                        assert_eq!(get(&db, String::from("hello")).unwrap(), "world");
+                        assert_eq!(tx_versions(&db, tx_id), 1);
+                    } else {
+                        assert!(get(&db, String::from("hello")).is_err());
+                        assert_eq!(tx_versions(&db, tx_id), 0);
+                    }
+                }
+                cleanup("recover_set");
+            }
+        }
+
+        #[test]
+        fn test_recover_multi_set() {
+            let keys = ["a", "b", "c"];
+            for &point in CRASH_POINTS.iter() {
+                {
+                    let (db, tx_id, _) = crash_during("recover_multi_set", point, |_, t| {
+                        for key in keys.iter() {
+                            t.write(kv_prefix(key), Some(key.as_bytes()));
+                        }
+                    });
+                    let committed = point == CrashPoint::Committed;
+                    for key in keys.iter() {
+                        assert_eq!(get(&db, key.to_string()).is_ok(), committed);
+                    }
+                    assert_eq!(tx_versions(&db, tx_id), if committed { 3 } else { 0 });
+                }
+                cleanup("recover_multi_set");
+            }
+        }
+
+        #[test]
+        fn test_recover_insert_row() {
+            for &point in CRASH_POINTS.iter() {
+                {
+                    let mut colvals = HashMap::new();
+                    colvals.insert("name".to_string(), "ada".to_string());
+                    let (db, tx_id, id) = crash_during("recover_insert_row", point, |db, t| {
+                        write_new_row(db, t, "crashtable", &colvals)
+                    });
+                    let row = get_row(&db, "crashtable", id);
+                    let by_name =
+                        get_by_col(&db, "crashtable", "name".to_string(), "ada".to_string());
+                    // A column plus the index entries for it and the id
+                    if point == CrashPoint::Committed {
+                        assert_eq!(row.get("name").unwrap(), "ada");
+                        assert_eq!(by_name.unwrap(), row);
+                        assert_eq!(tx_versions(&db, tx_id), 3);
+                    } else {
+                        assert!(row.is_empty());
+                        assert!(by_name.is_none());
+                        assert_eq!(tx_versions(&db, tx_id), 0);
+                    }
+                }
+                cleanup("recover_insert_row");
+            }
+        }
+
+        #[test]
+        fn test_as_of() {
+            {
+                let mut db = setup("as_of");
+                set(&mut db, String::from("a"), String::from("1")).unwrap();
+                let first = db.next_tx_id - 1;
+                set(&mut db, String::from("a"), String::from("2")).unwrap();
+                set(&mut db, String::from("b"), String::from("3")).unwrap();
+                let second = db.next_tx_id - 1;
+                delete(&mut db, String::from("a")).unwrap();
+                let third = db.next_tx_id - 1;
+
+                assert_eq!(get_as_of(&db, String::from("a"), first).unwrap(), "1");
+                assert_eq!(get_as_of(&db, String::from("a"), second).unwrap(), "2");
+                assert!(get_as_of(&db, String::from("a"), third).is_err());
+                let err = get_as_of(&db, String::from("a"), u64::MAX).unwrap_err();
+                assert!(is_invalid(&err));
+                let scan_as_of = |tx_id| {
+                    let opts = ScanOptions {
+                        as_of: Some(tx_id),
+                        ..ScanOptions::default()
+                    };
+                    values(scan_range(&db, &opts).unwrap())
+                };
+                assert_eq!(scan_as_of(first), vec!["1".to_string()]);
+                assert_eq!(scan_as_of(second), vec!["2".to_string(), "3".to_string()]);
+
+                let mut colvals = HashMap::new();
+                colvals.insert("foo".to_string(), "bar".to_string());
+                let id = insert_row(&mut db, "astable", &colvals).unwrap();
+                let inserted = db.next_tx_id - 1;
+                colvals.insert("foo".to_string(), "baz".to_string());
+                update_row(&mut db, "astable", id, &colvals).unwrap();
+                let row = get_row_as_of(&db, "astable", id, inserted).unwrap();
+                assert_eq!(row.get("foo").unwrap(), "bar");
+                assert!(get_row_as_of(&db, "astable", id, third).unwrap().is_empty());
+
+                vacuum(&mut db);
+                let err = get_as_of(&db, String::from("a"), first).unwrap_err();
+                assert!(is_invalid(&err));
+            }
+            cleanup("as_of");
+        }
+
+        #[test]
+        fn test_history() {
+            {
+                let mut db = setup("history");
+                set(&mut db, String::from("a"), String::from("1")).unwrap();
+                let first = db.next_tx_id - 1;
+                set(&mut db, String::from("a"), String::from("2")).unwrap();
+                delete(&mut db, String::from("a")).unwrap();
+                let last = db.next_tx_id - 1;
+
+                let versions = history(&db, String::from("a"), None, None, None).unwrap();
+                let values: Vec<Option<String>> =
+                    versions.iter().map(|v| v.value.clone()).collect();
+                assert_eq!(
+                    values,
+                    vec![None, Some("2".to_string()), Some("1".to_string())]
+                );
+                assert_eq!(versions[0].tx_id, last);
+                assert!(versions.iter().all(|v| v.state.unwrap().is_committed()));
+
+                let versions =
+                    history(&db, String::from("a"), Some(first), Some(last - 1), Some(1)).unwrap();
+                assert_eq!(versions.len(), 1);
+                assert_eq!(versions[0].value, Some("2".to_string()));
+
+                let mut colvals = HashMap::new();
+                colvals.insert("foo".to_string(), "bar".to_string());
+                let id = insert_row(&mut db, "histtable", &colvals).unwrap();
+                colvals.insert("foo".to_string(), "baz".to_string());
+                update_row(&mut db, "histtable", id, &colvals).unwrap();
+                let versions = row_history(&db, "histtable", id, "foo", None, None, None).unwrap();
+                assert_eq!(versions.len(), 2);
+                assert_eq!(versions[0].value, Some("baz".to_string()));
+            }
+            cleanup("history");
+        }
+
+        #[test]
+        fn test_scan_range() {
+            {
+                let mut db = setup("scan_range");
+                for key in &["a", "b", "c", "d", "e"] {
+                    set(&mut db, key.to_string(), key.to_uppercase()).unwrap();
+                }
+                let before_delete = db.next_tx_id - 1;
+                delete(&mut db, String::from("c")).unwrap();
+                let keys = |page: &ScanPage| -> Vec<String> {
+                    page.items.iter().map(|(k, _)| k.clone()).collect()
+                };
+
+                let page = scan_range(
+                    &db,
+                    &ScanOptions {
+                        start: Bound::Excluded(String::from("a")),
+                        end: Bound::Included(String::from("d")),
+                        ..ScanOptions::default()
+                    },
+                )
+                .unwrap();
+                assert_eq!(page.items[0], ("b".to_string(), "B".to_string()));
+                assert_eq!(keys(&page), vec!["b", "d"]);
+                assert!(page.cursor.is_none());
+
+                // Page backwards through everything, two at a time
+                let mut opts = ScanOptions {
+                    reverse: true,
+                    limit: Some(2),
+                    ..ScanOptions::default()
+                };
+                let mut pages = Vec::new();
+                loop {
+                    let page = scan_range(&db, &opts).unwrap();
+                    pages.push(keys(&page));
+                    match page.cursor {
+                        Some(cursor) => opts.cursor = Some(cursor),
+                        None => break,
+                    }
+                }
+                assert_eq!(pages, vec![vec!["e", "d"], vec!["b", "a"]]);
+
+                let opts = ScanOptions {
+                    end: Bound::Excluded(String::from("d")),
+                    as_of: Some(before_delete),
+                    ..ScanOptions::default()
+                };
+                assert_eq!(keys(&scan_range(&db, &opts).unwrap()), vec!["a", "b", "c"]);
+
+                // A full page only gets a cursor if something visible is left
+                delete(&mut db, String::from("e")).unwrap();
+                let mut opts = ScanOptions {
+                    limit: Some(3),
+                    ..ScanOptions::default()
+                };
+                let page = scan_range(&db, &opts).unwrap();
+                assert_eq!(keys(&page), vec!["a", "b", "d"]);
+                assert!(page.cursor.is_none());
+                opts.limit = Some(0);
+                assert!(is_invalid(&scan_range(&db, &opts).unwrap_err()));
+
+                // Scans in a transaction see its own writes
+                let tx = begin(&mut db);
+                tx_set(&mut db, tx, String::from("c"), String::from("C2")).unwrap();
+                tx_delete(&mut db, tx, String::from("a")).unwrap();
+                tx_incr(&mut db, tx, String::from("b"), 1).unwrap();
+                opts.limit = Some(2);
+                let page = tx_scan_range(&mut db, tx, &opts).unwrap();
+                assert_eq!(
+                    page.items,
+                    vec![
+                        ("b".to_string(), "1".to_string()),
+                        ("c".to_string(), "C2".to_string())
+                    ]
+                );
+                opts.cursor = page.cursor;
+                let page = tx_scan_range(&mut db, tx, &opts).unwrap();
+                assert_eq!(keys(&page), vec!["d"]);
+                assert!(page.cursor.is_none());
+
+                let opts = ScanOptions {
+                    cursor: Some(String::from("zz")),
+                    ..ScanOptions::default()
+                };
+                assert!(scan_range(&db, &opts).is_err());
+            }
+            cleanup("scan_range");
+        }
+
+        #[test]
+        fn test_prefix_scan() {
+            {
+                let mut db = setup("prefix_scan");
+                for key in &["user:4/a", "user:42", "user:42/a", "user:42/b", "user:43/a"] {
+                    set(&mut db, key.to_string(), key.to_uppercase()).unwrap();
+                }
+                set(&mut db, String::from("user:42/a"), String::from("new")).unwrap();
+                delete(&mut db, String::from("user:42/b")).unwrap();
+                assert_eq!(
+                    prefix_scan(&db, String::from("user:42")).unwrap(),
+                    vec![
+                        ("user:42".to_string(), "USER:42".to_string()),
+                        ("user:42/a".to_string(), "new".to_string())
+                    ]
+                );
+                assert!(prefix_scan(&db, String::from("nobody")).unwrap().is_empty());
+
+                // Prefixes page like any other scan and narrow its bounds
+                let mut opts = ScanOptions {
+                    prefix: Some(String::from("user:4")),
+                    limit: Some(2),
+                    ..ScanOptions::default()
+                };
+                let page = scan_range(&db, &opts).unwrap();
+                assert_eq!(page.items[1].0, "user:42");
+                opts.cursor = page.cursor;
+                let page = scan_range(&db, &opts).unwrap();
+                assert_eq!(page.items[0].0, "user:42/a");
+                assert_eq!(page.items[1].0, "user:43/a");
+                assert!(page.cursor.is_none());
+                opts.cursor = None;
+                opts.start = Bound::Excluded(String::from("user:42"));
+                opts.end = Bound::Excluded(String::from("user:43"));
+                let page = scan_range(&db, &opts).unwrap();
+                assert_eq!(
+                    page.items,
+                    vec![("user:42/a".to_string(), "new".to_string())]
+                );
+            }
+            cleanup("prefix_scan");
+        }
+
+        #[test]
+        fn test_interactive_tx() {
+            {
+                let mut db = setup("interactive_tx");
+                set(&mut db, String::from("balance"), String::from("10")).unwrap();
+                let tx = begin(&mut db);
+                let balance: u64 = tx_get(&mut db, tx, String::from("balance"))
+                    .unwrap()
+                    .parse()
+                    .unwrap();
+                tx_set(
+                    &mut db,
+                    tx,
+                    String::from("balance"),
+                    (balance + 5).to_string(),
+                )
+                .unwrap();
+
+                // Own writes are visible inside the tx but not outside it
+                assert_eq!(tx_get(&mut db, tx, String::from("balance")).unwrap(), "15");
+                assert_eq!(get(&db, String::from("balance")).unwrap(), "10");
+
+                commit(&mut db, tx).unwrap();
+                assert_eq!(get(&db, String::from("balance")).unwrap(), "15");
+                assert_eq!(mem_get(&db, String::from("balance")).unwrap(), "15");
+                assert!(tx_get(&mut db, tx, String::from("balance")).is_err());
+            }
+            cleanup("interactive_tx");
+        }
+
+        #[test]
+        fn test_rollback() {
+            {
+                let mut db = setup("rollback");
+                let tx = begin(&mut db);
+                tx_set(&mut db, tx, String::from("hello"), String::from("world")).unwrap();
+                let mut record = HashMap::new();
+                record.insert("foo".to_string(), "bar".to_string());
+                let id = tx_insert_row(&mut db, tx, "testtable", &record).unwrap();
+                assert_eq!(
+                    tx_get_row(&mut db, tx, "testtable", id)
+                        .unwrap()
+                        .get("foo")
+                        .unwrap(),
+                    "bar"
+                );
+                rollback(&mut db, tx).unwrap();
+
+                assert_eq!(
+                    get(&db, String::from("hello")),
+                    Err("Not found!".to_string())
+                );
+                assert!(get_row(&db, "testtable", id).is_empty());
+                assert!(commit(&mut db, tx).is_err());
+            }
+            cleanup("rollback");
+        }
+
+        #[test]
+        fn test_abort_idle() {
+            {
+                let mut db = setup("abort_idle");
+                let idle = begin(&mut db);
+                tx_set(&mut db, idle, String::from("a"), String::from("1")).unwrap();
+                set(&mut db, String::from("b"), String::from("1")).unwrap();
+                set(&mut db, String::from("b"), String::from("2")).unwrap();
+                // The open transaction might still read the old value
+                vacuum(&mut db);
+                assert_eq!(versions(&db, "b"), 2);
+                assert!(db.tx_floor <= idle);
+
+                assert_eq!(abort_idle(&mut db, TX_IDLE_TIMEOUT), 0);
+                assert_eq!(abort_idle(&mut db, 0), 1);
+                assert_eq!(
+                    tx_get(&mut db, idle, String::from("a")),
+                    Err(format!("Unknown transaction: {}", idle))
+                );
+                vacuum(&mut db);
+                assert_eq!(versions(&db, "b"), 1);
+                assert!(db.tx_floor > idle);
+                assert!(get(&db, String::from("a")).is_err());
+            }
+            cleanup("abort_idle");
+        }
+
+        #[test]
+        fn test_tx_snapshot() {
+            {
+                let mut db = setup("tx_snapshot");
+                set(&mut db, String::from("1"), String::from("old")).unwrap();
+                let reader = begin(&mut db);
+                let writer = begin(&mut db);
+                tx_set(&mut db, writer, String::from("1"), String::from("new")).unwrap();
+                tx_set(&mut db, writer, String::from("2"), String::from("added")).unwrap();
+                commit(&mut db, writer).unwrap();
+                set(&mut db, String::from("3"), String::from("later")).unwrap();
+
+                // Nothing committed after the reader began is visible to it
+                assert_eq!(tx_get(&mut db, reader, String::from("1")).unwrap(), "old");
+                assert_eq!(
+                    values(tx_scan_range(&mut db, reader, &ScanOptions::default()).unwrap()),
+                    vec!["old".to_string()]
+                );
+                rollback(&mut db, reader).unwrap();
+                assert_eq!(
+                    scan(&db, "1".to_string(), "3".to_string()).unwrap(),
+                    vec!["new".to_string(), "added".to_string(), "later".to_string()]
+                );
+            }
+            cleanup("tx_snapshot");
+        }
+
+        #[test]
+        fn test_write_conflict() {
+            {
+                let mut db = setup("write_conflict");
+                let first = begin(&mut db);
+                let second = begin(&mut db);
+                tx_set(&mut db, first, String::from("hello"), String::from("first")).unwrap();
+                tx_set(
+                    &mut db,
+                    second,
+                    String::from("hello"),
+                    String::from("second"),
+                )
+                .unwrap();
+                tx_set(&mut db, second, String::from("foo"), String::from("bar")).unwrap();
+                commit(&mut db, first).unwrap();
+                let err = commit(&mut db, second).unwrap_err();
+                assert!(err.starts_with("Serialization failure"));
+                assert_eq!(tx_state(&db, second), Some(TxState::Aborted));
+                assert_eq!(get(&db, String::from("hello")).unwrap(), "first");
+                assert_eq!(get(&db, String::from("foo")), Err("Not found!".to_string()));
 
-            record.insert("foo".to_string(), "baz".to_string());
-            update_row(&mut db, "testtable", id, &record).unwrap();
-            let rec = get_row(&mut db, "testtable", id);
-            assert_eq!(rec.get("foo").unwrap(), "baz");
+                // Single-call writes take part in the check as well
+                let tx = begin(&mut db);
+                tx_set(&mut db, tx, String::from("hello"), String::from("stale")).unwrap();
+                set(&mut db, String::from("hello"), String::from("direct")).unwrap();
+                assert!(commit(&mut db, tx).is_err());
+                assert_eq!(get(&db, String::from("hello")).unwrap(), "direct");
+
+                // Writing different keys is fine
+                let first = begin(&mut db);
+                let second = begin(&mut db);
+                tx_set(&mut db, first, String::from("a"), String::from("1")).unwrap();
+                tx_set(&mut db, second, String::from("b"), String::from("2")).unwrap();
+                commit(&mut db, second).unwrap();
+                commit(&mut db, first).unwrap();
+            }
+            cleanup("write_conflict");
         }
 
-        #[test]
-        fn test_get_by_col() {
-            let mut db = setup();
-            let mut record0 = HashMap::new();
-            record0.insert("foo".to_string(), "bar".to_string());
-            record0.insert("other_key".to_string(), "other_value".to_string());
-            insert_row(&mut db, "testtable", &record0).unwrap();
-            let mut record1 = HashMap::new();
-            record1.insert("foo".to_string(), "not-looked-for".to_string());
-            record1.insert("other_key".to_string(), "not-loooked-for".to_string());
-            insert_row(&mut db, "testtable", &record1).unwrap();
-            let rec = get_by_col(&mut db, "testtable", "foo".to_string(), "bar".to_string()).unwrap();
-            assert_eq!(rec.get("other_key").unwrap(), "other_value");
+        // Two doctors are on call and each may go off call as long as the
+        // other one stays on. Run concurrently under snapshot isolation both
+        // see the other on call and both leave (write skew).
+        fn doctors_leave(db: &mut DBState, isolation: Isolation) -> Vec<Result<String, String>> {
+            let mut on_call = HashMap::new();
+            on_call.insert("on_call".to_string(), "yes".to_string());
+            let alice = insert_row(db, "doctors", &on_call).unwrap();
+            let bob = insert_row(db, "doctors", &on_call).unwrap();
+            let mut off_call = HashMap::new();
+            off_call.insert("on_call".to_string(), "no".to_string());
+
+            let txs = [
+                (begin_with(db, isolation), alice, bob),
+                (begin_with(db, isolation), bob, alice),
+            ];
+            for (tx, me, other) in txs.iter() {
+                let other = tx_get_row(db, *tx, "doctors", *other).unwrap();
+                if other.get("on_call").unwrap() == "yes" {
+                    tx_update_row(db, *tx, "doctors", *me, &off_call).unwrap();
+                }
+            }
+            txs.iter().map(|(tx, _, _)| commit(db, *tx)).collect()
         }
 
         #[test]
-        fn test_get() {
+        fn test_write_skew() {
             {
-                let mut db = setup();
-                set(&mut db, String::from("hello"), String::from("world")).unwrap();
-                let res = get(&mut db, String::from("hello")).unwrap();
-                assert_eq!(res, String::from("world"));
+                let mut db = setup("write_skew");
+                let results = doctors_leave(&mut db, Isolation::Snapshot);
+                assert!(results.iter().all(|r| r.is_ok()));
+
+                let results = doctors_leave(&mut db, Isolation::Serializable);
+                assert_eq!(results.iter().filter(|r| r.is_ok()).count(), 1);
+                assert!(results
+                    .iter()
+                    .any(|r| matches!(r, Err(e) if e.starts_with("Serialization failure"))));
             }
-            cleanup();
+            cleanup("write_skew");
         }
 
         #[test]
-        fn test_scan() {
+        fn test_serializable_reads() {
             {
-                let mut db = setup();
-                let mut keyvals = HashMap::new();
-                set(&mut db, "3".to_string(), "should-be-ignored".to_string()).unwrap();
-                keyvals.insert("1".to_string(), "first".to_string());
-                keyvals.insert("2".to_string(), "second".to_string());
-                keyvals.insert("3".to_string(), "third".to_string());
-                keyvals.insert("4".to_string(), "fourth".to_string());
-                keyvals.insert("5".to_string(), "fifth".to_string());
-                multi_set(&mut db, keyvals).unwrap();
-                let res = scan(&mut db, "2".to_string(), "3".to_string());
-                assert_eq!(res, vec!["second".to_string(), "third".to_string()]);
+                let mut db = setup("serializable_reads");
+                set(&mut db, String::from("1"), String::from("a")).unwrap();
+
+                // Each tx scans a range the other one writes into
+                let first = begin_with(&mut db, Isolation::Serializable);
+                let second = begin_with(&mut db, Isolation::Serializable);
+                assert_eq!(
+                    tx_scan_range(&mut db, first, &ScanOptions::default())
+                        .unwrap()
+                        .items
+                        .len(),
+                    1
+                );
+                assert_eq!(
+                    tx_scan_range(&mut db, second, &ScanOptions::default())
+                        .unwrap()
+                        .items
+                        .len(),
+                    1
+                );
+                tx_set(&mut db, first, String::from("2"), String::from("b")).unwrap();
+                tx_set(&mut db, second, String::from("3"), String::from("c")).unwrap();
+                commit(&mut db, first).unwrap();
+                assert!(commit(&mut db, second).is_err());
+
+                // Index lookups are tracked as well
+                let mut record = HashMap::new();
+                record.insert("name".to_string(), "ada".to_string());
+                insert_row(&mut db, "people", &record).unwrap();
+                let first = begin_with(&mut db, Isolation::Serializable);
+                let second = begin_with(&mut db, Isolation::Serializable);
+                let found = tx_get_by_col(
+                    &mut db,
+                    first,
+                    "people",
+                    "name".to_string(),
+                    "grace".to_string(),
+                );
+                assert_eq!(found, Ok(None));
+                tx_set(&mut db, first, String::from("4"), String::from("d")).unwrap();
+                assert_eq!(
+                    tx_get(&mut db, second, String::from("4")),
+                    Err("Not found!".to_string())
+                );
+                record.insert("name".to_string(), "grace".to_string());
+                tx_insert_row(&mut db, second, "people", &record).unwrap();
+                commit(&mut db, second).unwrap();
+                assert!(commit(&mut db, first).is_err());
+
+                // Transactions that don't depend on each other both commit
+                let first = begin_with(&mut db, Isolation::Serializable);
+                let second = begin_with(&mut db, Isolation::Serializable);
+                tx_get(&mut db, first, String::from("1")).unwrap();
+                tx_get(&mut db, second, String::from("2")).unwrap();
+                tx_set(&mut db, first, String::from("5"), String::from("e")).unwrap();
+                tx_set(&mut db, second, String::from("6"), String::from("f")).unwrap();
+                commit(&mut db, first).unwrap();
+                commit(&mut db, second).unwrap();
+                assert!(db.ssi_committed.is_empty());
             }
-            cleanup();
+            cleanup("serializable_reads");
         }
 
         #[test]
         fn test_crashed() {
             {
-                let mut db = setup_crashed();
+                let db = setup_crashed();
                 assert_eq!(
-                    get(&mut db, String::from("hello")),
+                    get(&db, String::from("hello")),
                     Err("Not found!".to_string())
                 );
-                let res = get(&mut db, String::from("good_hello")).unwrap();
+                let res = get(&db, String::from("good_hello")).unwrap();
                 assert_eq!(res, String::from("good_world"));
             }
             cleanup_crashed();
@@ -602,35 +4117,1192 @@ mod db {
     }
 }
 
+// Shared with the expiry reaper, see `spawn_reaper`
+type Db = Arc<RwLock<db::DBState>>;
+
+const REAP_INTERVAL: Duration = Duration::from_secs(10);
+
 #[get("/")]
 fn index() -> &'static str {
     "Eza DB!"
 }
 
-#[get("/get/<key>")]
-fn get(state: State<RwLock<db::DBState>>, key: String) -> String {
+#[get("/get/<key>?<tx>&<as_of>")]
+fn get(
+    state: State<Db>,
+    _query: ValidQuery,
+    key: String,
+    tx: Option<u64>,
+    as_of: Option<u64>,
+) -> TextResult {
+    // Reads outside of a transaction only need a snapshot, so they can share
+    // the lock
+    match (tx, as_of) {
+        (Some(tx), _) => db::tx_get(&mut state.write().unwrap(), tx, key),
+        (None, Some(as_of)) => db::get_as_of(&state.read().unwrap(), key, as_of),
+        (None, None) => db::get(&state.read().unwrap(), key),
+    }
+    .map_err(text_error)
+}
+
+// The status code to report an error from `db` with
+fn error_status(e: &str) -> Status {
+    if db::is_not_found(e) {
+        Status::NotFound
+    } else if db::is_conflict(e) {
+        Status::Conflict
+    } else if db::is_invalid(e) {
+        Status::BadRequest
+    } else {
+        Status::InternalServerError
+    }
+}
+
+// Errors of the plain text routes come back as the message itself. A
+// panic while holding the lock would poison it for every later request, so
+// routes never unwrap errors a client can cause.
+type TextResult = Result<String, status::Custom<String>>;
+
+fn text_error(e: String) -> status::Custom<String> {
+    status::Custom(error_status(&e), e)
+}
+
+// Responses of the JSON API. Errors come back as {"error": "..."}.
+type JsonResult = Result<Json<Value>, status::Custom<Json<Value>>>;
+
+fn json_error(status: Status, msg: String) -> status::Custom<Json<Value>> {
+    status::Custom(status, Json(json!({ "error": msg })))
+}
+
+fn db_error(e: String) -> status::Custom<Json<Value>> {
+    json_error(error_status(&e), e)
+}
+
+// Bodies are read into memory in full, so they can't be larger than this
+const BODY_LIMIT: u64 = 1024 * 1024;
+
+fn read_body(data: Data) -> Result<String, status::Custom<Json<Value>>> {
+    let mut body = Vec::new();
+    data.open()
+        .take(BODY_LIMIT + 1)
+        .read_to_end(&mut body)
+        .map_err(|e| json_error(Status::BadRequest, format!("Invalid body: {}", e)))?;
+    if body.len() as u64 > BODY_LIMIT {
+        return Err(json_error(
+            Status::PayloadTooLarge,
+            format!("Body is larger than {} bytes", BODY_LIMIT),
+        ));
+    }
+    String::from_utf8(body)
+        .map_err(|_| json_error(Status::BadRequest, String::from("Body is not valid UTF-8")))
+}
+
+// Rocket reads an optional query parameter that doesn't parse as missing,
+// which would quietly run e.g. `?tx=abc` outside of the transaction. Routes
+// with such parameters take this guard to get a 400 instead.
+struct ValidQuery;
+
+const NUMBER_PARAMS: &[&str] = &["tx", "ttl", "as_of", "version", "limit", "from", "to"];
+const BOOL_PARAMS: &[&str] = &["serializable", "reverse", "if_absent"];
+
+// Why a request failed the ValidQuery guard, for the 400 catcher
+struct QueryError(String);
+
+impl<'a, 'r> FromRequest<'a, 'r> for ValidQuery {
+    type Error = String;
+
+    fn from_request(req: &'a Request<'r>) -> request::Outcome<ValidQuery, String> {
+        for item in req.raw_query_items().into_iter().flatten() {
+            let (key, value) = item.key_value_decoded();
+            let valid = if NUMBER_PARAMS.contains(&key.as_str()) {
+                value.parse::<u64>().is_ok()
+            } else if BOOL_PARAMS.contains(&key.as_str()) {
+                matches!(value.as_str(), "true" | "false" | "on" | "off" | "")
+            } else {
+                true
+            };
+            if !valid {
+                let msg = format!("Invalid {}: {}", key, value);
+                req.local_cache(|| QueryError(msg.clone()));
+                return Outcome::Failure((Status::BadRequest, msg));
+            }
+        }
+        Outcome::Success(ValidQuery)
+    }
+}
+
+// Requests that don't reach a route get JSON errors like the ones from the
+// routes
+fn catcher_error(req: &Request, msg: &str) -> Json<Value> {
+    Json(json!({ "error": format!("{}: {}", msg, req.uri()) }))
+}
+
+#[catch(400)]
+fn bad_request(req: &Request) -> Json<Value> {
+    match req.local_cache(|| QueryError(String::new())) {
+        QueryError(msg) if !msg.is_empty() => Json(json!({ "error": msg })),
+        _ => catcher_error(req, "Bad request"),
+    }
+}
+
+#[catch(404)]
+fn not_found(req: &Request) -> Json<Value> {
+    catcher_error(req, "No route for")
+}
+
+#[catch(422)]
+fn unprocessable_entity(req: &Request) -> Json<Value> {
+    catcher_error(req, "Could not parse")
+}
+
+#[catch(500)]
+fn internal_error(req: &Request) -> Json<Value> {
+    catcher_error(req, "Internal error handling")
+}
+
+#[get("/kv/<key>?<tx>&<as_of>")]
+fn kv_get(
+    state: State<Db>,
+    _query: ValidQuery,
+    key: String,
+    tx: Option<u64>,
+    as_of: Option<u64>,
+) -> JsonResult {
+    let value = match (tx, as_of) {
+        (Some(tx), _) => db::tx_get(&mut state.write().unwrap(), tx, key.clone()),
+        (None, Some(as_of)) => db::get_as_of(&state.read().unwrap(), key.clone(), as_of),
+        (None, None) => db::get(&state.read().unwrap(), key.clone()),
+    }
+    .map_err(db_error)?;
+    Ok(Json(json!({ "key": key, "value": value })))
+}
+
+// A JSON body looks like {"value": "..."}, optionally with an "expected"
+// value to compare and set against
+fn parse_kv_body(body: &str) -> Result<(String, Option<String>), status::Custom<Json<Value>>> {
+    let body: Value = serde_json::from_str(body)
+        .map_err(|e| json_error(Status::BadRequest, format!("Invalid JSON: {}", e)))?;
+    let field = |name: &str| match body.get(name) {
+        None | Some(Value::Null) => Ok(None),
+        Some(Value::String(s)) => Ok(Some(s.clone())),
+        Some(_) => Err(json_error(
+            Status::BadRequest,
+            format!("{} must be a string", name),
+        )),
+    };
+    match field("value")? {
+        Some(value) => Ok((value, field("expected")?)),
+        None => Err(json_error(
+            Status::BadRequest,
+            String::from("Missing value"),
+        )),
+    }
+}
+
+#[derive(FromForm)]
+struct PutOptions {
+    tx: Option<u64>,
+    ttl: Option<u64>,
+    // Only write if the key was last written by this tx
+    version: Option<u64>,
+    if_absent: Option<bool>,
+}
+
+// Takes the value as the raw body, or as JSON if the content type says so
+#[put("/kv/<key>?<opts..>", data = "<data>")]
+fn kv_put(
+    state: State<Db>,
+    _query: ValidQuery,
+    key: String,
+    opts: Form<PutOptions>,
+    content_type: Option<&ContentType>,
+    data: Data,
+) -> JsonResult {
+    let body = read_body(data)?;
+    let (value, expected) = match content_type {
+        Some(ct) if ct.is_json() => parse_kv_body(&body)?,
+        _ => (body, None),
+    };
+    let PutOptions {
+        tx,
+        ttl,
+        version,
+        if_absent,
+    } = opts.into_inner();
+    let mut db = state.write().unwrap();
+    let res = match (expected, version, if_absent.unwrap_or(false)) {
+        (None, None, false) => match tx {
+            Some(tx) => db::tx_set_with_ttl(&mut db, tx, key.clone(), value.clone(), ttl),
+            None => db::set_with_ttl(&mut db, key.clone(), value.clone(), ttl),
+        },
+        _ if tx.is_some() || ttl.is_some() => {
+            return Err(json_error(
+                Status::BadRequest,
+                String::from("Conditional writes can't use a transaction or a TTL"),
+            ))
+        }
+        (Some(expected), None, false) => {
+            db::compare_and_set(&mut db, key.clone(), expected, value.clone())
+        }
+        (None, Some(version), false) => {
+            db::set_if_version(&mut db, key.clone(), version, value.clone())
+        }
+        (None, None, true) => db::set_if_absent(&mut db, key.clone(), value.clone()),
+        _ => {
+            return Err(json_error(
+                Status::BadRequest,
+                String::from("Only one condition can be given"),
+            ))
+        }
+    };
+    res.map_err(db_error)?;
+    Ok(Json(json!({ "key": key, "value": value })))
+}
+
+#[delete("/kv/<key>?<tx>")]
+fn kv_delete(state: State<Db>, _query: ValidQuery, key: String, tx: Option<u64>) -> JsonResult {
+    let mut db = state.write().unwrap();
+    // Deleting a missing key would succeed, so check for it first
+    match tx {
+        Some(tx) => db::tx_get(&mut db, tx, key.clone())
+            .and_then(|_| db::tx_delete(&mut db, tx, key.clone())),
+        None => db::get(&db, key.clone()).and_then(|_| db::delete(&mut db, key.clone())),
+    }
+    .map_err(db_error)?;
+    Ok(Json(json!({ "key": key, "deleted": true })))
+}
+
+// Interactive transactions for the JSON API. The id that comes back is
+// passed as `?tx=` to the other routes.
+#[post("/txs?<serializable>")]
+fn tx_begin(state: State<Db>, _query: ValidQuery, serializable: Option<bool>) -> Json<Value> {
+    let mut db = state.write().unwrap();
+    let tx = db::begin_with(&mut db, isolation(serializable));
+    Json(json!({ "tx": tx }))
+}
+
+// A 409 means the transaction lost a conflict and was aborted
+#[post("/txs/<tx>/commit")]
+fn tx_commit(state: State<Db>, tx: u64) -> JsonResult {
+    db::commit(&mut state.write().unwrap(), tx).map_err(db_error)?;
+    Ok(Json(json!({ "tx": tx, "committed": true })))
+}
+
+#[post("/txs/<tx>/rollback")]
+fn tx_rollback(state: State<Db>, tx: u64) -> JsonResult {
+    db::rollback(&mut state.write().unwrap(), tx).map_err(db_error)?;
+    Ok(Json(json!({ "tx": tx, "committed": false })))
+}
+
+// Rows are sent as JSON objects of column names to values, and come back
+// with their id added
+fn row_json(id: u64, row: HashMap<String, String>) -> Value {
+    let mut obj: serde_json::Map<String, Value> = row
+        .into_iter()
+        .map(|(col, v)| (col, Value::String(v)))
+        .collect();
+    obj.insert(String::from("id"), json!(id));
+    Value::Object(obj)
+}
+
+// Parses a flat JSON object such as a row or a batch of keys. Numbers and
+// booleans are stored as text.
+fn parse_object(body: &str) -> Result<HashMap<String, String>, status::Custom<Json<Value>>> {
+    let bad_request = |msg: String| json_error(Status::BadRequest, msg);
+    let body: Value =
+        serde_json::from_str(body).map_err(|e| bad_request(format!("Invalid JSON: {}", e)))?;
+    let obj = match body {
+        Value::Object(obj) => obj,
+        _ => return Err(bad_request(String::from("Expected a JSON object"))),
+    };
+    let mut keyvals = HashMap::new();
+    for (key, value) in obj {
+        let value = match value {
+            Value::String(s) => s,
+            Value::Number(n) => n.to_string(),
+            Value::Bool(b) => b.to_string(),
+            _ => return Err(bad_request(format!("Value of {} must be a string", key))),
+        };
+        keyvals.insert(key, value);
+    }
+    Ok(keyvals)
+}
+
+#[post("/tables/<table>/rows?<tx>", data = "<data>")]
+fn insert_row(
+    state: State<Db>,
+    _query: ValidQuery,
+    table: String,
+    tx: Option<u64>,
+    data: Data,
+) -> Result<status::Created<Json<Value>>, status::Custom<Json<Value>>> {
+    let colvals = parse_object(&read_body(data)?)?;
+    let mut db = state.write().unwrap();
+    let id = match tx {
+        Some(tx) => db::tx_insert_row(&mut db, tx, &table, &colvals),
+        None => db::insert_row(&mut db, &table, &colvals),
+    }
+    .map_err(db_error)?;
+    let url = format!("/tables/{}/rows/{}", table, id);
+    Ok(status::Created(url, Some(Json(json!({ "id": id })))))
+}
+
+#[get("/tables/<table>/rows/<id>?<tx>&<as_of>")]
+fn get_row(
+    state: State<Db>,
+    _query: ValidQuery,
+    table: String,
+    id: u64,
+    tx: Option<u64>,
+    as_of: Option<u64>,
+) -> JsonResult {
+    let row = match (tx, as_of) {
+        (Some(tx), _) => db::tx_get_row(&mut state.write().unwrap(), tx, &table, id),
+        (None, Some(as_of)) => db::get_row_as_of(&state.read().unwrap(), &table, id, as_of),
+        (None, None) => Ok(db::get_row(&state.read().unwrap(), &table, id)),
+    }
+    .map_err(db_error)?;
+    if row.is_empty() {
+        return Err(json_error(Status::NotFound, String::from("Not found!")));
+    }
+    Ok(Json(row_json(id, row)))
+}
+
+// Only the given columns are changed
+#[patch("/tables/<table>/rows/<id>?<tx>", data = "<data>")]
+fn update_row(
+    state: State<Db>,
+    _query: ValidQuery,
+    table: String,
+    id: u64,
+    tx: Option<u64>,
+    data: Data,
+) -> JsonResult {
+    let colvals = parse_object(&read_body(data)?)?;
+    let mut db = state.write().unwrap();
+    let row = match tx {
+        Some(tx) => db::tx_update_row(&mut db, tx, &table, id, &colvals)
+            .and_then(|_| db::tx_get_row(&mut db, tx, &table, id)),
+        None => db::update_row(&mut db, &table, id, &colvals).map(|_| db::get_row(&db, &table, id)),
+    }
+    .map_err(db_error)?;
+    Ok(Json(row_json(id, row)))
+}
+
+// Looks rows up through the secondary index with a query of exactly one
+// column, e.g. /tables/users/rows?name=bob
+#[get("/tables/<table>/rows")]
+fn find_rows(state: State<Db>, table: String, uri: &Origin) -> JsonResult {
+    let query: Vec<&str> = uri.query().unwrap_or("").split('&').collect();
+    let (col, value) = match query[..] {
+        [pair] if pair.contains('=') => {
+            let mut parts = pair
+                .splitn(2, '=')
+                .map(|part| RawStr::from_str(part).url_decode());
+            match (parts.next().unwrap(), parts.next().unwrap()) {
+                (Ok(col), Ok(value)) => (col, value),
+                _ => {
+                    return Err(json_error(
+                        Status::BadRequest,
+                        String::from("Invalid query"),
+                    ))
+                }
+            }
+        }
+        _ => {
+            return Err(json_error(
+                Status::BadRequest,
+                String::from("Expected a query of one column, e.g. ?col=value"),
+            ))
+        }
+    };
+    let db = state.read().unwrap();
+    let rows: Vec<Value> = db::find_rows(&db, &table, col, value)
+        .into_iter()
+        .map(|(id, row)| row_json(id, row))
+        .collect();
+    Ok(Json(Value::Array(rows)))
+}
+
+// The number of key/value pairs `/scan` returns when no `limit` is given,
+// and the most it returns for one request
+const SCAN_LIMIT: usize = 100;
+const MAX_SCAN_LIMIT: usize = 10_000;
+// How many pairs are read each time the lock is taken while streaming
+const SCAN_CHUNK: usize = 100;
+
+#[derive(FromForm)]
+struct ScanQuery {
+    start: Option<String>,
+    end: Option<String>,
+    limit: Option<usize>,
+    reverse: Option<bool>,
+    cursor: Option<String>,
+    as_of: Option<u64>,
+    tx: Option<u64>,
+}
+
+// Streams a scan as NDJSON: a `{"type": "item", ...}` line per pair, then a
+// `{"type": "end", "cursor": ...}` line whose cursor fetches the next page,
+// or is null once the range is exhausted. Pairs are read a chunk at a time
+// so the lock isn't held while the response is written, which means each
+// chunk sees a fresh snapshot unless `as_of` or a transaction is given.
+struct ScanStream {
+    db: Db,
+    tx: Option<u64>,
+    opts: db::ScanOptions,
+    // Pairs left to send
+    remaining: usize,
+    buf: Vec<u8>,
+    pos: usize,
+    done: bool,
+}
+
+impl ScanStream {
+    // Reads the first chunk up front, so that e.g. a bad cursor or an unknown
+    // transaction is reported through the status
+    fn start(
+        db: Db,
+        tx: Option<u64>,
+        opts: db::ScanOptions,
+        limit: usize,
+    ) -> Result<ScanStream, String> {
+        let mut stream = ScanStream {
+            db,
+            tx,
+            opts,
+            remaining: limit,
+            buf: Vec::new(),
+            pos: 0,
+            done: false,
+        };
+        stream.next_chunk()?;
+        Ok(stream)
+    }
+
+    fn next_chunk(&mut self) -> Result<(), String> {
+        self.opts.limit = Some(self.remaining.min(SCAN_CHUNK));
+        let page = match self.tx {
+            Some(tx) => db::tx_scan_range(&mut self.db.write().unwrap(), tx, &self.opts),
+            None => db::scan_range(&self.db.read().unwrap(), &self.opts),
+        }?;
+        self.remaining -= page.items.len();
+        for (key, value) in page.items {
+            self.push_line(json!({ "type": "item", "key": key, "value": value }));
+        }
+        self.opts.cursor = page.cursor;
+        if self.opts.cursor.is_none() || self.remaining == 0 {
+            let end = json!({ "type": "end", "cursor": self.opts.cursor });
+            self.push_line(end);
+            self.done = true;
+        }
+        Ok(())
+    }
+
+    fn push_line(&mut self, line: Value) {
+        self.buf.extend_from_slice(line.to_string().as_bytes());
+        self.buf.push(b'\n');
+    }
+}
+
+impl Read for ScanStream {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        while self.pos == self.buf.len() && !self.done {
+            self.buf.clear();
+            self.pos = 0;
+            // The status has already been sent, so a failure ends the stream
+            // with an error line instead
+            if let Err(e) = self.next_chunk() {
+                self.push_line(json!({ "type": "error", "error": e }));
+                self.done = true;
+            }
+        }
+        let n = (&self.buf[self.pos..]).read(out)?;
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+type ScanResponse = Result<Content<Stream<ScanStream>>, status::Custom<Json<Value>>>;
+
+fn stream_scan(
+    db: &Db,
+    tx: Option<u64>,
+    limit: Option<usize>,
+    opts: db::ScanOptions,
+) -> ScanResponse {
+    let limit = limit.unwrap_or(SCAN_LIMIT);
+    if limit > MAX_SCAN_LIMIT {
+        return Err(json_error(
+            Status::BadRequest,
+            format!("Invalid limit: {}, at most {}", limit, MAX_SCAN_LIMIT),
+        ));
+    }
+    let stream = ScanStream::start(db.clone(), tx, opts, limit).map_err(db_error)?;
+    let ndjson = ContentType::new("application", "x-ndjson");
+    Ok(Content(ndjson, Stream::from(stream)))
+}
+
+// Key/value pairs from `start` to `end` inclusive, at most `limit` of them
+#[get("/scan?<query..>")]
+fn scan(state: State<Db>, _query: ValidQuery, query: Form<ScanQuery>) -> ScanResponse {
+    let query = query.into_inner();
+    let bound = |key: Option<String>| match key {
+        Some(key) => Bound::Included(key),
+        None => Bound::Unbounded,
+    };
+    let opts = db::ScanOptions {
+        start: bound(query.start),
+        end: bound(query.end),
+        reverse: query.reverse.unwrap_or(false),
+        cursor: query.cursor,
+        as_of: query.as_of,
+        ..db::ScanOptions::default()
+    };
+    stream_scan(&state, query.tx, query.limit, opts)
+}
+
+// Sets every key in a JSON object in one transaction
+#[post("/batch?<ttl>", data = "<data>")]
+fn batch(state: State<Db>, _query: ValidQuery, ttl: Option<u64>, data: Data) -> JsonResult {
+    let keyvals = parse_object(&read_body(data)?)?;
+    let count = keyvals.len();
+    let mut db = state.write().unwrap();
+    db::multi_set_with_ttl(&mut db, keyvals, ttl).map_err(db_error)?;
+    Ok(Json(json!({ "count": count })))
+}
+
+// One object per version. `kind` tells values apart from deltas, expired
+// values and deletes, which all have a null `value`.
+fn history_json(versions: Vec<db::Version>) -> Json<Value> {
+    let versions = versions
+        .into_iter()
+        .map(|v| {
+            let (state, committed_at) = match v.state {
+                // The commit time is lost once the state record is compacted
+                Some(db::TxState::Committed(0)) => ("committed", None),
+                Some(db::TxState::Committed(ts)) => ("committed", Some(ts)),
+                Some(db::TxState::Pending) => ("pending", None),
+                Some(db::TxState::Aborted) => ("aborted", None),
+                None => ("unknown", None),
+            };
+            let kind = match (&v.value, v.delta, v.expires_at) {
+                (Some(_), _, _) => "value",
+                (None, Some(_), _) => "delta",
+                (None, None, Some(_)) => "expired",
+                (None, None, None) => "deleted",
+            };
+            json!({
+                "tx_id": v.tx_id,
+                "state": state,
+                "committed_at": committed_at,
+                "kind": kind,
+                "value": v.value,
+                "delta": v.delta,
+                "expires_at": v.expires_at,
+            })
+        })
+        .collect();
+    Json(Value::Array(versions))
+}
+
+#[get("/history/<key>?<from>&<to>&<limit>")]
+fn history(
+    state: State<Db>,
+    _query: ValidQuery,
+    key: String,
+    from: Option<u64>,
+    to: Option<u64>,
+    limit: Option<usize>,
+) -> JsonResult {
+    let db = state.read().unwrap();
+    db::history(&db, key, from, to, limit)
+        .map(history_json)
+        .map_err(db_error)
+}
+
+#[get("/history/<table>/<id>/<col>?<from>&<to>&<limit>")]
+fn row_history(
+    state: State<Db>,
+    _query: ValidQuery,
+    table: String,
+    id: u64,
+    col: String,
+    from: Option<u64>,
+    to: Option<u64>,
+    limit: Option<usize>,
+) -> JsonResult {
+    let db = state.read().unwrap();
+    db::row_history(&db, &table, id, &col, from, to, limit)
+        .map(history_json)
+        .map_err(db_error)
+}
+
+#[derive(FromForm)]
+struct PrefixQuery {
+    limit: Option<usize>,
+    cursor: Option<String>,
+    as_of: Option<u64>,
+    tx: Option<u64>,
+}
+
+// Key/value pairs whose keys start with `p`, paged and streamed like `/scan`
+#[get("/prefix/<p>?<query..>")]
+fn prefix(
+    state: State<Db>,
+    _query: ValidQuery,
+    p: String,
+    query: Form<PrefixQuery>,
+) -> ScanResponse {
+    let query = query.into_inner();
+    let opts = db::ScanOptions {
+        prefix: Some(p),
+        cursor: query.cursor,
+        as_of: query.as_of,
+        ..db::ScanOptions::default()
+    };
+    stream_scan(&state, query.tx, query.limit, opts)
+}
+
+// Takes a JSON array of keys and returns an array with an object for each
+// key, in the same order. Missing keys have a null value.
+#[post("/multi_get", data = "<data>")]
+fn multi_get(state: State<Db>, data: Data) -> JsonResult {
+    let keys: Vec<String> = serde_json::from_str(&read_body(data)?).map_err(|e| {
+        json_error(
+            Status::BadRequest,
+            format!("Expected a JSON array of keys: {}", e),
+        )
+    })?;
+    let db = state.read().unwrap();
+    let found = db::multi_get(&db, keys)
+        .map_err(db_error)?
+        .into_iter()
+        .map(|(key, value)| json!({ "key": key, "value": value }))
+        .collect();
+    Ok(Json(Value::Array(found)))
+}
+
+// Conflicts are an expected outcome of a conditional write, so they're
+// reported with a 409. Anything else is a 500.
+fn conditional(res: Result<String, String>) -> TextResult {
+    res.map_err(text_error)
+}
+
+#[get("/cas/<key>/<expected>/<value>")]
+fn compare_and_set(state: State<Db>, key: String, expected: String, value: String) -> TextResult {
+    let mut db = state.write().unwrap();
+    conditional(db::compare_and_set(&mut db, key, expected, value))
+}
+
+#[get("/set_if_absent/<key>/<value>")]
+fn set_if_absent(state: State<Db>, key: String, value: String) -> TextResult {
+    let mut db = state.write().unwrap();
+    conditional(db::set_if_absent(&mut db, key, value))
+}
+
+#[get("/set_if_version/<key>/<version>/<value>")]
+fn set_if_version(state: State<Db>, key: String, version: u64, value: String) -> TextResult {
+    let mut db = state.write().unwrap();
+    conditional(db::set_if_version(&mut db, key, version, value))
+}
+
+#[get("/set/<key>/<value>?<tx>&<ttl>")]
+fn set(
+    state: State<Db>,
+    _query: ValidQuery,
+    key: String,
+    value: String,
+    tx: Option<u64>,
+    ttl: Option<u64>,
+) -> TextResult {
+    let mut db = state.write().unwrap();
+    match tx {
+        Some(tx) => db::tx_set_with_ttl(&mut db, tx, key, value, ttl),
+        None => db::set_with_ttl(&mut db, key, value, ttl),
+    }
+    .map_err(text_error)
+}
+
+#[get("/incr/<key>/<delta>?<tx>")]
+fn incr(
+    state: State<Db>,
+    _query: ValidQuery,
+    key: String,
+    delta: i64,
+    tx: Option<u64>,
+) -> TextResult {
+    let mut db = state.write().unwrap();
+    match tx {
+        Some(tx) => db::tx_incr(&mut db, tx, key, delta),
+        None => db::incr(&mut db, key, delta),
+    }
+    .map_err(text_error)
+}
+
+#[get("/decr/<key>/<delta>")]
+fn decr(state: State<Db>, key: String, delta: i64) -> TextResult {
+    let mut db = state.write().unwrap();
+    db::decr(&mut db, key, delta).map_err(text_error)
+}
+
+#[get("/delete/<key>?<tx>")]
+fn delete(state: State<Db>, _query: ValidQuery, key: String, tx: Option<u64>) -> TextResult {
+    let mut db = state.write().unwrap();
+    match tx {
+        Some(tx) => db::tx_delete(&mut db, tx, key),
+        None => db::delete(&mut db, key),
+    }
+    .map_err(text_error)
+}
+
+#[get("/begin?<serializable>")]
+fn begin(state: State<Db>, _query: ValidQuery, serializable: Option<bool>) -> String {
+    let mut db = state.write().unwrap();
+    db::begin_with(&mut db, isolation(serializable)).to_string()
+}
+
+fn isolation(serializable: Option<bool>) -> db::Isolation {
+    match serializable {
+        Some(true) => db::Isolation::Serializable,
+        _ => db::Isolation::Snapshot,
+    }
+}
+
+// A transaction that loses a write conflict or a serialization check is
+// aborted and gets a 409, so the client knows to retry it
+#[get("/commit/<tx>")]
+fn commit(state: State<Db>, tx: u64) -> TextResult {
+    let mut db = state.write().unwrap();
+    db::commit(&mut db, tx).map_err(text_error)
+}
+
+#[get("/rollback/<tx>")]
+fn rollback(state: State<Db>, tx: u64) -> TextResult {
     let mut db = state.write().unwrap();
-    db::get(&mut db, key).unwrap()
+    db::rollback(&mut db, tx).map_err(text_error)
 }
 
-#[get("/set/<key>/<value>")]
-fn set(state: State<RwLock<db::DBState>>, key: String, value: String) -> String {
+#[get("/vacuum")]
+fn vacuum(state: State<Db>) -> String {
     let mut db = state.write().unwrap();
-    db::set(&mut db, key, value).unwrap()
+    format!("Removed {} versions", db::vacuum(&mut db))
+}
+
+// Periodically deletes expired keys in the background
+fn spawn_reaper(db: Db) {
+    thread::spawn(move || loop {
+        thread::sleep(REAP_INTERVAL);
+        db::abort_idle(&mut db.write().unwrap(), db::TX_IDLE_TIMEOUT);
+        // Only the tombstones need the db exclusively. Keys are reaped in
+        // batches, letting other requests in between.
+        loop {
+            let expired = db::expired_keys(&db.read().unwrap());
+            let more = expired.len() == db::REAP_BATCH;
+            if let Err(e) = db::delete_expired(&mut db.write().unwrap(), expired) {
+                eprintln!("Failed to delete expired keys: {}", e);
+                break;
+            }
+            if !more {
+                break;
+            }
+        }
+    });
+}
+
+fn rocket(db: Db) -> rocket::Rocket {
+    rocket::ignite()
+        .manage(db)
+        .register(catchers![
+            bad_request,
+            not_found,
+            unprocessable_entity,
+            internal_error
+        ])
+        .mount(
+            "/",
+            routes![
+                index,
+                set,
+                get,
+                delete,
+                begin,
+                commit,
+                rollback,
+                vacuum,
+                history,
+                row_history,
+                prefix,
+                multi_get,
+                compare_and_set,
+                set_if_absent,
+                set_if_version,
+                incr,
+                decr,
+                kv_get,
+                kv_put,
+                kv_delete,
+                tx_begin,
+                tx_commit,
+                tx_rollback,
+                insert_row,
+                get_row,
+                update_row,
+                find_rows,
+                scan,
+                batch
+            ],
+        )
 }
 
 fn main() {
     let db_path = "data".to_string();
-    let wal_file = OpenOptions::new()
-        .create(true)
-        .read(true)
-        .append(true)
-        .open("wal.db")
-        .unwrap();
-    let db = db::initialize_db(db_path, wal_file);
+    let db = Arc::new(RwLock::new(db::initialize_db(db_path, "wal".to_string())));
+    spawn_reaper(db.clone());
+    rocket(db).launch();
+}
 
-    rocket::ignite()
-        .manage(RwLock::new(db))
-        .mount("/", routes![index, set, get])
-        .launch();
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rocket::local::{Client, LocalResponse};
+    use std::fs;
+
+    // Each test gets its own directories so they can run alongside the db
+    // tests
+    fn setup(name: &str) -> Client {
+        let db = db::initialize_db(
+            format!("{}_route_data", name),
+            format!("{}_route_wal", name),
+        );
+        Client::new(rocket(Arc::new(RwLock::new(db)))).expect("Cannot start rocket.")
+    }
+
+    fn cleanup(name: &str) {
+        fs::remove_dir_all(format!("{}_route_data", name)).expect("Can't clean up test db.");
+        fs::remove_dir_all(format!("{}_route_wal", name)).expect("Can't clean up test wal.");
+    }
+
+    fn body(mut response: LocalResponse) -> Value {
+        serde_json::from_str(&response.body_string().unwrap()).unwrap()
+    }
+
+    #[test]
+    fn test_kv_routes() {
+        {
+            let client = setup("kv");
+            // A raw body is the value as is, even if it looks like JSON
+            let response = client.put("/kv/a").body(r#"{"value": "1"}"#).dispatch();
+            assert_eq!(response.status(), Status::Ok);
+            let value = body(client.get("/kv/a").dispatch())["value"].clone();
+            assert_eq!(value, r#"{"value": "1"}"#);
+            let response = client
+                .put("/kv/b")
+                .header(ContentType::JSON)
+                .body(r#"{"value": "1"}"#)
+                .dispatch();
+            assert_eq!(body(response), json!({ "key": "b", "value": "1" }));
+            let response = client
+                .put("/kv/b")
+                .header(ContentType::JSON)
+                .body(r#"{"value": 2}"#)
+                .dispatch();
+            assert_eq!(response.status(), Status::BadRequest);
+            let response = client
+                .put("/kv/b")
+                .header(ContentType::JSON)
+                .body("not json")
+                .dispatch();
+            assert_eq!(response.status(), Status::BadRequest);
+
+            // Compare and set
+            let cas = |expected: &str, value: &str| {
+                client
+                    .put("/kv/b")
+                    .header(ContentType::JSON)
+                    .body(json!({ "value": value, "expected": expected }).to_string())
+                    .dispatch()
+                    .status()
+            };
+            assert_eq!(cas("1", "2"), Status::Ok);
+            assert_eq!(cas("1", "3"), Status::Conflict);
+            assert_eq!(body(client.get("/kv/b").dispatch())["value"], "2");
+
+            // Set if absent
+            let response = client.put("/kv/c?if_absent=true").body("1").dispatch();
+            assert_eq!(response.status(), Status::Ok);
+            let response = client.put("/kv/c?if_absent=true").body("2").dispatch();
+            assert_eq!(response.status(), Status::Conflict);
+
+            // Set if version
+            let versions = body(client.get("/history/c").dispatch());
+            let version = versions[0]["tx_id"].as_u64().unwrap();
+            let url = format!("/kv/c?version={}", version);
+            assert_eq!(
+                client.put(url.clone()).body("3").dispatch().status(),
+                Status::Ok
+            );
+            assert_eq!(
+                client.put(url).body("4").dispatch().status(),
+                Status::Conflict
+            );
+            assert_eq!(body(client.get("/kv/c").dispatch())["value"], "3");
+
+            // Conditions can't be combined, or used with a tx or a TTL
+            let response = client
+                .put("/kv/c?if_absent=true&version=1")
+                .body("5")
+                .dispatch();
+            assert_eq!(response.status(), Status::BadRequest);
+            let response = client
+                .put("/kv/c?if_absent=true&ttl=10")
+                .body("5")
+                .dispatch();
+            assert_eq!(response.status(), Status::BadRequest);
+            let tx = client.get("/begin").dispatch().body_string().unwrap();
+            let url = format!("/kv/c?if_absent=true&tx={}", tx);
+            assert_eq!(
+                client.put(url).body("5").dispatch().status(),
+                Status::BadRequest
+            );
+            assert_eq!(body(client.get("/kv/c").dispatch())["value"], "3");
+
+            // Bodies are limited in size
+            let large = vec![b'x'; BODY_LIMIT as usize + 1];
+            let response = client.put("/kv/d").body(large).dispatch();
+            assert_eq!(response.status(), Status::PayloadTooLarge);
+            assert_eq!(client.get("/kv/d").dispatch().status(), Status::NotFound);
+
+            // Requests that don't reach a route still get a JSON error
+            let response = client.put("/kv/c?tx=abc").body("6").dispatch();
+            assert_eq!(response.status(), Status::BadRequest);
+            assert_eq!(body(response), json!({ "error": "Invalid tx: abc" }));
+            let response = client.get("/tables/people/rows/abc").dispatch();
+            assert_eq!(response.status(), Status::NotFound);
+            assert_eq!(response.content_type(), Some(ContentType::JSON));
+            assert!(body(response)["error"].is_string());
+            assert_eq!(body(client.get("/kv/c").dispatch())["value"], "3");
+
+            // Keys can hold newlines, so they're read as a JSON array
+            let response = client.put("/kv/x%0Ay").body("7").dispatch();
+            assert_eq!(response.status(), Status::Ok);
+            let response = client
+                .post("/multi_get")
+                .body(r#"["c", "d", "x\ny"]"#)
+                .dispatch();
+            assert_eq!(
+                body(response),
+                json!([
+                    { "key": "c", "value": "3" },
+                    { "key": "d", "value": null },
+                    { "key": "x\ny", "value": "7" }
+                ])
+            );
+            let response = client.post("/multi_get").body("c\nd").dispatch();
+            assert_eq!(response.status(), Status::BadRequest);
+
+            // Deletes
+            let response = client.delete("/kv/c").dispatch();
+            assert_eq!(body(response), json!({ "key": "c", "deleted": true }));
+            assert_eq!(client.delete("/kv/c").dispatch().status(), Status::NotFound);
+            assert_eq!(client.get("/kv/c").dispatch().status(), Status::NotFound);
+            assert_eq!(client.get("/get/c").dispatch().status(), Status::NotFound);
+        }
+        cleanup("kv");
+    }
+
+    #[test]
+    fn test_row_routes() {
+        {
+            let client = setup("rows");
+            let response = client
+                .post("/tables/people/rows")
+                .body(r#"{"name": "ada", "age": 36}"#)
+                .dispatch();
+            assert_eq!(response.status(), Status::Created);
+            let url = response.headers().get_one("Location").unwrap().to_string();
+            let id = body(response)["id"].as_u64().unwrap();
+            let row = body(client.get(url.clone()).dispatch());
+            assert_eq!(row, json!({ "id": id, "name": "ada", "age": "36" }));
+
+            let response = client.post("/tables/people/rows").body("[1, 2]").dispatch();
+            assert_eq!(response.status(), Status::BadRequest);
+            let response = client.get("/tables/people/rows/12345").dispatch();
+            assert_eq!(response.status(), Status::NotFound);
+            let response = client.get("/tables/people/rows?name=ada&age=36").dispatch();
+            assert_eq!(response.status(), Status::BadRequest);
+            let response = client
+                .patch("/tables/people/rows/12345")
+                .body(r#"{"name": "bob"}"#)
+                .dispatch();
+            assert_eq!(response.status(), Status::NotFound);
+            let response = client
+                .post("/tables/people/rows")
+                .body(r#"{"id": 12345, "name": "bob"}"#)
+                .dispatch();
+            assert_eq!(response.status(), Status::BadRequest);
+            let response = client.patch(url.clone()).body(r#"{"id": 1}"#).dispatch();
+            assert_eq!(response.status(), Status::BadRequest);
+            let response = client.post("/tables/people/rows").body("{}").dispatch();
+            assert_eq!(response.status(), Status::BadRequest);
+
+            // Rows sharing a value are all found, and a PATCH moves the row
+            // to its new value in the index
+            let response = client
+                .post("/tables/people/rows")
+                .body(r#"{"name": "ada"}"#)
+                .dispatch();
+            let other = body(response)["id"].as_u64().unwrap();
+            let found = body(client.get("/tables/people/rows?name=ada").dispatch());
+            assert_eq!(found.as_array().unwrap().len(), 2);
+            let response = client.patch(url).body(r#"{"name": "grace"}"#).dispatch();
+            assert_eq!(body(response)["name"], "grace");
+            let found = body(client.get("/tables/people/rows?name=ada").dispatch());
+            assert_eq!(found, json!([{ "id": other, "name": "ada" }]));
+            let found = body(client.get("/tables/people/rows?name=grace").dispatch());
+            assert_eq!(found, json!([{ "id": id, "name": "grace", "age": "36" }]));
+        }
+        cleanup("rows");
+    }
+
+    fn lines(mut response: LocalResponse) -> Vec<Value> {
+        let body = response.body_string().unwrap();
+        body.lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_scan_and_batch_routes() {
+        {
+            let client = setup("scan");
+            let response = client
+                .post("/batch")
+                .body(r#"{"a": "1", "b": "2", "c": 3}"#)
+                .dispatch();
+            assert_eq!(body(response), json!({ "count": 3 }));
+            let response = client.post("/batch").body(r#"{"d": [4]}"#).dispatch();
+            assert_eq!(response.status(), Status::BadRequest);
+            assert_eq!(client.get("/kv/d").dispatch().status(), Status::NotFound);
+
+            // Pages end in the cursor for the next one, or null at the end
+            let page = lines(client.get("/scan?limit=2").dispatch());
+            assert_eq!(page.len(), 3);
+            assert_eq!(page[0], json!({ "type": "item", "key": "a", "value": "1" }));
+            assert_eq!(page[1], json!({ "type": "item", "key": "b", "value": "2" }));
+            assert_eq!(page[2]["type"], "end");
+            let cursor = page[2]["cursor"].as_str().unwrap().to_string();
+            let page = lines(
+                client
+                    .get(format!("/scan?limit=2&cursor={}", cursor))
+                    .dispatch(),
+            );
+            assert_eq!(
+                page,
+                vec![
+                    json!({ "type": "item", "key": "c", "value": "3" }),
+                    json!({ "type": "end", "cursor": null })
+                ]
+            );
+            let page = lines(client.get("/scan?start=b&reverse=true").dispatch());
+            assert_eq!(page.len(), 3);
+            assert_eq!(page[0]["key"], "c");
+
+            let response = client.get("/scan?limit=0").dispatch();
+            assert_eq!(response.status(), Status::BadRequest);
+            let response = client.get("/scan?limit=10001").dispatch();
+            assert_eq!(response.status(), Status::BadRequest);
+            let response = client.get("/scan?cursor=zz").dispatch();
+            assert_eq!(response.status(), Status::BadRequest);
+
+            // A transaction sees its own writes
+            let tx = body(client.post("/txs").dispatch())["tx"].as_u64().unwrap();
+            let url = format!("/kv/aa?tx={}", tx);
+            assert_eq!(client.put(url).body("5").dispatch().status(), Status::Ok);
+            let page = lines(client.get(format!("/scan?end=b&tx={}", tx)).dispatch());
+            assert_eq!(page.len(), 4);
+            assert_eq!(
+                page[1],
+                json!({ "type": "item", "key": "aa", "value": "5" })
+            );
+            assert_eq!(lines(client.get("/scan?end=b").dispatch()).len(), 3);
+            let response = client.get("/scan?tx=12345").dispatch();
+            assert_eq!(response.status(), Status::NotFound);
+
+            // Pages larger than a chunk are read in several goes
+            let keyvals: HashMap<String, String> = (0..250)
+                .map(|i| (format!("k{:03}", i), i.to_string()))
+                .collect();
+            let response = client
+                .post("/batch")
+                .body(json!(keyvals).to_string())
+                .dispatch();
+            assert_eq!(body(response), json!({ "count": 250 }));
+            let page = lines(client.get("/scan?start=k&limit=220").dispatch());
+            assert_eq!(page.len(), 221);
+            assert_eq!(page[0]["key"], "k000");
+            assert_eq!(page[219]["key"], "k219");
+            let cursor = page[220]["cursor"].as_str().unwrap().to_string();
+            let page = lines(client.get(format!("/scan?cursor={}", cursor)).dispatch());
+            assert_eq!(page.len(), 31);
+            assert_eq!(page[0]["key"], "k220");
+            assert_eq!(page[30], json!({ "type": "end", "cursor": null }));
+
+            // Prefix scans page the same way
+            let page = lines(client.get("/prefix/k1?limit=60").dispatch());
+            assert_eq!(page.len(), 61);
+            assert_eq!(
+                page[0],
+                json!({ "type": "item", "key": "k100", "value": "100" })
+            );
+            let cursor = page[60]["cursor"].as_str().unwrap().to_string();
+            let url = format!("/prefix/k1?cursor={}", cursor);
+            let page = lines(client.get(url).dispatch());
+            assert_eq!(page.len(), 41);
+            assert_eq!(page[39]["key"], "k199");
+            assert_eq!(page[40], json!({ "type": "end", "cursor": null }));
+            let page = lines(client.get("/prefix/b").dispatch());
+            assert_eq!(page[0], json!({ "type": "item", "key": "b", "value": "2" }));
+            assert_eq!(page.len(), 2);
+        }
+        cleanup("scan");
+    }
+
+    #[test]
+    fn test_tx_routes() {
+        {
+            let client = setup("txs");
+            let first = body(client.post("/txs").dispatch())["tx"].as_u64().unwrap();
+            let second = body(client.post("/txs").dispatch())["tx"].as_u64().unwrap();
+            for (tx, value) in &[(first, "1"), (second, "2")] {
+                let url = format!("/kv/a?tx={}", tx);
+                assert_eq!(client.put(url).body(*value).dispatch().status(), Status::Ok);
+            }
+            // Uncommitted writes are only visible to their own transaction
+            assert_eq!(client.get("/kv/a").dispatch().status(), Status::NotFound);
+            let value = body(client.get(format!("/kv/a?tx={}", first)).dispatch())["value"].clone();
+            assert_eq!(value, "1");
+
+            let response = client.post(format!("/txs/{}/commit", first)).dispatch();
+            assert_eq!(body(response), json!({ "tx": first, "committed": true }));
+            let response = client.post(format!("/txs/{}/commit", second)).dispatch();
+            assert_eq!(response.status(), Status::Conflict);
+            // The loser was aborted, so it's no longer open
+            let response = client.post(format!("/txs/{}/rollback", second)).dispatch();
+            assert_eq!(response.status(), Status::NotFound);
+            assert_eq!(body(client.get("/kv/a").dispatch())["value"], "1");
+
+            let third = body(client.post("/txs?serializable=true").dispatch())["tx"]
+                .as_u64()
+                .unwrap();
+            let url = format!("/kv/a?tx={}", third);
+            assert_eq!(client.put(url).body("3").dispatch().status(), Status::Ok);
+            let response = client.post(format!("/txs/{}/rollback", third)).dispatch();
+            assert_eq!(body(response), json!({ "tx": third, "committed": false }));
+            assert_eq!(body(client.get("/kv/a").dispatch())["value"], "1");
+            let response = client.get(format!("/kv/a?tx={}", third)).dispatch();
+            assert_eq!(response.status(), Status::NotFound);
+        }
+        cleanup("txs");
+    }
 }